@@ -1,28 +1,138 @@
 use std::collections::HashMap;
-use std::time::Instant;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use http::Request;
 use http::Response;
+use http::Method;
+use connection::ConnWriter;
+#[cfg(feature = "h2c")]
+use http2::Responder;
+use util::timer::Timer;
+use executor::Spawner;
+use util::threadpool::PoolHandle;
+use super::deferred::{Deferred, Target};
+use super::future::Complete;
 
 pub struct Context {
     pub request: Request,
     pub response: Response,
     pub contexts: HashMap<String, Value>,
-    stop: bool
+    stop: bool,
+    target: Option<Target>,
+    timer: Option<Arc<Timer>>,
+    spawner: Option<Spawner>,
+    pool: Option<PoolHandle>,
+    deferred: Option<Deferred>,
+    pattern: Option<String>,
 }
 
 impl Context {
     pub fn new(request: Request) -> Context {
-        let response = Response::empty(200);
+        let mut response = Response::empty(200);
+        response.set_head(request.method == Method::Head);
 
         Context {
             request: request,
             response: response,
             contexts: HashMap::new(),
-            stop: false
+            stop: false,
+            target: None,
+            timer: None,
+            spawner: None,
+            pool: None,
+            deferred: None,
+            pattern: None,
         }
     }
 
+    pub fn set_conn(&mut self, conn: Option<ConnWriter>) {
+        self.target = conn.map(Target::Conn);
+    }
+
+    /// HTTP/2 的请求，延迟响应写回到这个流
+    #[cfg(feature = "h2c")]
+    pub fn set_responder(&mut self, responder: Responder) {
+        self.target = Some(Target::Stream(responder));
+    }
+
+    pub fn set_timer(&mut self, timer: Arc<Timer>) {
+        self.timer = Some(timer);
+    }
+
+    /// 共用的定时器，比如给事件流定时发送保持连接的注释，定时任务应该很快返回
+    pub fn timer(&self) -> Option<&Timer> {
+        self.timer.as_ref().map(|timer| &**timer)
+    }
+
+    pub fn set_spawner(&mut self, spawner: Option<Spawner>) {
+        self.spawner = spawner;
+    }
+
+    pub fn set_pool(&mut self, pool: Option<PoolHandle>) {
+        self.pool = pool;
+    }
+
+    /// 处理请求的线程池，可以把计算任务分出去并等待结果
+    pub fn pool(&self) -> Option<&PoolHandle> {
+        self.pool.as_ref()
+    }
+
+    pub fn set_pattern(&mut self, pattern: String) {
+        self.pattern = Some(pattern);
+    }
+
+    /// 匹配到的路由模式，没有匹配到路由时为 None
+    pub fn pattern(&self) -> Option<&str> {
+        self.pattern.as_ref().map(|pattern| pattern.as_str())
+    }
+
+    /// 在事件循环上执行 Future，完成后用它的结果响应
+    pub fn spawn<F>(&mut self, future: F)
+        where F: Future<Output = Response> + Send + 'static
+    {
+        let deferred = self.defer();
+
+        match self.spawner {
+            Some(ref spawner) => spawner.spawn(Complete::new(future, deferred)),
+            None => {
+                deferred.complete(Response::empty(501));
+            }
+        }
+    }
+
+    /// 暂不响应，返回的句柄可在之后任意线程完成响应
+    pub fn defer(&mut self) -> Deferred {
+        let deferred = Deferred::new(self.target.clone(), self.request.method == Method::Head);
+        self.deferred = Some(deferred.clone());
+        deferred
+    }
+
+    /// 同 defer，超时还没完成时用 fallback 响应
+    pub fn defer_timeout(&mut self, timeout: Duration, fallback: Response) -> Deferred {
+        let deferred = self.defer();
+
+        if let Some(ref timer) = self.timer {
+            let expired = deferred.clone();
+            let handle = timer.schedule(timeout, move || {
+                expired.complete(fallback);
+            });
+            deferred.set_timeout(handle);
+        }
+
+        deferred
+    }
+
+    pub fn is_deferred(&self) -> bool {
+        self.deferred.is_some()
+    }
+
+    /// 最近一次 defer 返回的句柄
+    pub fn deferred(&self) -> Option<&Deferred> {
+        self.deferred.as_ref()
+    }
+
     pub fn stop(&mut self) {
         self.stop = true;
     }
@@ -0,0 +1,133 @@
+use std::sync::{Arc, Mutex};
+
+use connection::ConnWriter;
+use buffer::Buffer;
+use http::{self, Response};
+use util::timer::TimerHandle;
+use util::sync::lock;
+#[cfg(feature = "h2c")]
+use http2::Responder;
+
+/// 延迟响应写回的地方
+#[derive(Clone)]
+pub enum Target {
+    /// HTTP/1.1 连接
+    Conn(ConnWriter),
+    /// HTTP/2 连接上的一个流
+    #[cfg(feature = "h2c")]
+    Stream(Responder),
+}
+
+impl Target {
+    fn is_closed(&self) -> bool {
+        match *self {
+            Target::Conn(ref conn) => conn.is_closed(),
+            #[cfg(feature = "h2c")]
+            Target::Stream(ref responder) => responder.is_closed(),
+        }
+    }
+}
+
+/// 延迟响应句柄
+///
+/// 处理函数返回后不占用线程池，在任意线程调用 `complete` 写回响应。
+#[derive(Clone)]
+pub struct Deferred {
+    target: Arc<Mutex<Option<Target>>>,
+    timeout: Arc<Mutex<Option<TimerHandle>>>,
+    completion: Arc<Mutex<Completion>>,
+    head: bool,
+}
+
+//完成时的状态码和等待完成的回调
+#[derive(Default)]
+struct Completion {
+    status: Option<u16>,
+    hooks: Vec<Box<dyn FnOnce(u16) + Send>>,
+}
+
+impl Deferred {
+    /// head 为 true 时是 HEAD 请求，完成时不写出响应体
+    pub fn new(target: Option<Target>, head: bool) -> Deferred {
+        Deferred {
+            target: Arc::new(Mutex::new(target)),
+            timeout: Arc::new(Mutex::new(None)),
+            completion: Arc::new(Mutex::new(Completion::default())),
+            head: head,
+        }
+    }
+
+    /// 只有第一次调用生效，已完成或连接已关闭时返回 false
+    pub fn complete(&self, response: Response) -> bool {
+        let target = match lock(&self.target).take() {
+            Some(target) => target,
+            None => return false,
+        };
+
+        if let Some(timeout) = lock(&self.timeout).take() {
+            timeout.cancel();
+        }
+
+        let hooks = {
+            let mut completion = lock(&self.completion);
+            completion.status = Some(response.status_code.0);
+            ::std::mem::take(&mut completion.hooks)
+        };
+
+        for hook in hooks {
+            hook(response.status_code.0);
+        }
+
+        let conn = match target {
+            Target::Conn(conn) => conn,
+            #[cfg(feature = "h2c")]
+            Target::Stream(responder) => return responder.send(response),
+        };
+
+        let mut writer = Buffer::new();
+
+        match http::write_response(&mut writer, response, self.head) {
+            Some(stream) => {
+                //在事件流的锁里发送响应，保证之后的事件排在响应后面
+                let mut sent = false;
+                stream.attach(conn.clone(), |pending| {
+                    writer.extend_from_slice(&pending);
+                    sent = conn.send(writer.into_vec()).is_ok();
+                });
+                sent
+            },
+            None => conn.send(writer.into_vec()).is_ok(),
+        }
+    }
+
+    /// 完成时用响应的状态码调用 f，已经完成时立即调用
+    pub fn on_complete<F>(&self, f: F)
+        where F: FnOnce(u16) + Send + 'static
+    {
+        let status = {
+            let mut completion = lock(&self.completion);
+
+            match completion.status {
+                Some(status) => status,
+                None => {
+                    completion.hooks.push(Box::new(f));
+                    return;
+                }
+            }
+        };
+
+        f(status);
+    }
+
+    pub fn is_completed(&self) -> bool {
+        lock(&self.target).is_none()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        lock(&self.target).as_ref().map_or(true, |target| target.is_closed())
+    }
+
+    pub fn set_timeout(&self, timeout: TimerHandle) {
+        *lock(&self.timeout) = Some(timeout);
+    }
+}
@@ -0,0 +1,57 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+
+use http::Response;
+use super::context::Context;
+use super::deferred::Deferred;
+
+/// Future 完成后写回延迟的响应
+pub struct Complete {
+    future: Pin<Box<dyn Future<Output = Response> + Send + 'static>>,
+    deferred: Deferred,
+}
+
+impl Complete {
+    pub fn new<F>(future: F, deferred: Deferred) -> Complete
+        where F: Future<Output = Response> + Send + 'static
+    {
+        Complete {
+            future: Box::pin(future),
+            deferred: deferred,
+        }
+    }
+}
+
+//Future panic 被丢弃时还没有响应，返回 500；已经完成时 complete 不会生效
+impl Drop for Complete {
+    fn drop(&mut self) {
+        self.deferred.complete(Response::empty(500));
+    }
+}
+
+impl Future for Complete {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<()> {
+        match self.future.as_mut().poll(cx) {
+            Poll::Ready(response) => {
+                self.deferred.complete(response);
+                Poll::Ready(())
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// 把返回 Future 的处理函数包装成普通处理函数
+///
+/// Future 不能借用 Context，需要的请求数据要先取出来移进 Future。
+pub fn async_handle<H, F>(handle: H) -> impl Fn(&mut Context) + Send + Sync + 'static
+    where H: Fn(&mut Context) -> F + Send + Sync + 'static, F: Future<Output = Response> + Send + 'static
+{
+    move |context: &mut Context| {
+        let future = handle(context);
+        context.spawn(future);
+    }
+}
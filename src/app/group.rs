@@ -1,40 +1,139 @@
+use http::Method;
+use error::MioResult;
 use super::route::Route;
 use super::context::Context;
 use super::middleware::Middleware;
 
+/// 路由分组，组内的路由共用路径前缀和中间件
+///
+/// 分组可以嵌套，外层分组的中间件也作用于内层分组的路由。
 pub struct Group {
     pub routes: Vec<Route>,
     prefix: String,
     pub before: Vec<Middleware>,
     pub after: Vec<Middleware>,
+    /// 嵌套的分组
+    pub groups: Vec<Group>,
 }
 
 impl Group {
     pub fn new(prefix: &str) -> Group {
         Group {
             routes: Vec::new(),
-            prefix: prefix.to_owned(),
+            prefix: join(prefix, ""),
             before: Vec::new(),
             after: Vec::new(),
+            groups: Vec::new(),
         }
     }
 
-    fn add<H>(&mut self, method: &str, pattern: &str, handle: H) -> &mut Route
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// 添加嵌套分组，前缀接在当前分组的前缀后面
+    pub fn group<F>(&mut self, prefix: &str, f: F)
+        where F: FnOnce(&mut Group)
+    {
+        let mut group = Group::new(&join(&self.prefix, prefix));
+        f(&mut group);
+        self.groups.push(group);
+    }
+
+    //路径模式写错时返回错误，冲突由 App::group 注册时检查
+    fn add<H>(&mut self, methods: Vec<Method>, pattern: &str, handle: H) -> MioResult<&mut Route>
         where H: Fn(&mut Context) + Send + Sync + 'static
     {
         let route = Route::new(
-            method.parse().unwrap(),
-            self.prefix.clone() + pattern,
+            methods,
+            join(&self.prefix, pattern),
             Box::new(handle),
-        );
+        )?;
 
         self.routes.push(route);
-        self.routes.last_mut().unwrap()
+        Ok(self.routes.last_mut().unwrap())
+    }
+
+    /// 分组内的路由在处理函数之前执行
+    pub fn before_route<H>(&mut self, handle: H)
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.before.push(Middleware { inner: Box::new(handle) });
+    }
+
+    /// 分组内的路由在处理函数之后执行
+    pub fn after_route<H>(&mut self, handle: H)
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.after.push(Middleware { inner: Box::new(handle) });
+    }
+
+    pub fn get<H>(&mut self, pattern: &str, handle: H) -> MioResult<&mut Route>
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.add(vec![Method::Get], pattern, handle)
+    }
+
+    pub fn post<H>(&mut self, pattern: &str, handle: H) -> MioResult<&mut Route>
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.add(vec![Method::Post], pattern, handle)
+    }
+
+    pub fn put<H>(&mut self, pattern: &str, handle: H) -> MioResult<&mut Route>
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.add(vec![Method::Put], pattern, handle)
+    }
+
+    pub fn delete<H>(&mut self, pattern: &str, handle: H) -> MioResult<&mut Route>
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.add(vec![Method::Delete], pattern, handle)
+    }
+
+    pub fn patch<H>(&mut self, pattern: &str, handle: H) -> MioResult<&mut Route>
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.add(vec![Method::Patch], pattern, handle)
+    }
+
+    pub fn head<H>(&mut self, pattern: &str, handle: H) -> MioResult<&mut Route>
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.add(vec![Method::Head], pattern, handle)
+    }
+
+    pub fn options<H>(&mut self, pattern: &str, handle: H) -> MioResult<&mut Route>
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.add(vec![Method::Options], pattern, handle)
+    }
+
+    /// 匹配所有方法，同一路径上注册了具体方法的路由优先
+    pub fn any<H>(&mut self, pattern: &str, handle: H) -> MioResult<&mut Route>
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.add(Vec::new(), pattern, handle)
     }
 
-    pub fn get<H>(&mut self, pattern: &str, handle: H) -> &mut Route
+    /// 一个处理函数注册多个方法，也可以用 `Method::NonStandard` 注册 PROPFIND 之类的扩展方法
+    pub fn route<H>(&mut self, methods: &[Method], pattern: &str, handle: H) -> MioResult<&mut Route>
         where H: Fn(&mut Context) + Send + Sync + 'static
     {
-        self.add(&stringify!(get).to_uppercase(), pattern, handle)
+        self.add(methods.to_vec(), pattern, handle)
     }
 }
+
+/// 拼接前缀和路径，去掉多余的 /
+pub fn join(prefix: &str, path: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    let path = path.trim_start_matches('/');
+
+    match (prefix.is_empty(), path.is_empty()) {
+        (true, true) => "/".to_owned(),
+        (true, false) => format!("/{}", path),
+        (false, true) => prefix.to_owned(),
+        (false, false) => format!("{}/{}", prefix, path),
+    }
+}
\ No newline at end of file
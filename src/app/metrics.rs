@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use stats::StatsSnapshot;
+use util::sync::lock;
+use super::context::{Context, Value};
+
+/// 请求开始时间在 Context::contexts 里的键
+pub const START: &'static str = "sunflower.metrics.start";
+
+/// 没有匹配到路由的请求统一记在这个标签下，避免原始路径撑爆序列数量
+const UNMATCHED: &'static str = "unmatched";
+
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+struct Histogram {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+struct Registry {
+    //(method, route, status class) -> count
+    requests: HashMap<(String, String, &'static str), u64>,
+    //(method, route) -> histogram
+    durations: HashMap<(String, String), Histogram>,
+}
+
+/// Prometheus 指标
+///
+/// 按路由模式（不是原始路径）统计请求数、状态码类别和耗时分布，
+/// 通过 `App::metrics` 安装后在配置的路径上输出文本格式。
+#[derive(Clone)]
+pub struct Metrics {
+    path: String,
+    buckets: Arc<Vec<f64>>,
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            path: "/metrics".to_owned(),
+            buckets: Arc::new(BUCKETS.to_vec()),
+            registry: Arc::new(Mutex::new(Registry::default())),
+        }
+    }
+
+    /// 输出指标的路径，默认 /metrics
+    pub fn path(&mut self, path: &str) -> &mut Metrics {
+        self.path = path.to_owned();
+        self
+    }
+
+    /// 耗时直方图的桶上限，单位秒
+    pub fn buckets(&mut self, buckets: &[f64]) -> &mut Metrics {
+        let mut buckets = buckets.to_vec();
+        buckets.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        buckets.dedup();
+        self.buckets = Arc::new(buckets);
+        self
+    }
+
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+
+    /// 记录一个处理完的请求，开始时间取自 begin 中间件放进 contexts 的时间
+    ///
+    /// 延迟响应和 Future 的请求等真正写回响应时再记录，用那时的状态码和耗时。
+    pub fn observe(&self, context: &Context) {
+        let method = context.request.method.as_str().to_owned();
+        let route = context.pattern().unwrap_or(UNMATCHED).to_owned();
+        let start = context.contexts.get(START).and_then(Value::as_instant).cloned();
+
+        match context.deferred() {
+            Some(deferred) => {
+                let metrics = self.clone();
+                deferred.on_complete(move |status| metrics.record(method, route, status, start));
+            }
+            None => self.record(method, route, context.response.status_code.0, start),
+        }
+    }
+
+    fn record(&self, method: String, route: String, status: u16, start: Option<Instant>) {
+        let class = status_class(status);
+        let elapsed = start.map(|start| start.elapsed());
+
+        let mut registry = lock(&self.registry);
+
+        *registry.requests.entry((method.clone(), route.clone(), class)).or_insert(0) += 1;
+
+        if let Some(elapsed) = elapsed {
+            let seconds = elapsed.as_secs_f64();
+            let buckets = self.buckets.len();
+
+            let histogram = registry.durations.entry((method, route)).or_insert_with(|| Histogram {
+                counts: vec![0; buckets],
+                sum: 0.0,
+                count: 0,
+            });
+
+            for (i, le) in self.buckets.iter().enumerate() {
+                if seconds <= *le {
+                    histogram.counts[i] += 1;
+                }
+            }
+
+            histogram.sum += seconds;
+            histogram.count += 1;
+        }
+    }
+
+    /// 输出 Prometheus 文本格式，带上服务器统计
+    pub fn render(&self, stats: Option<&StatsSnapshot>) -> String {
+        let mut out = String::new();
+        let registry = lock(&self.registry);
+
+        let mut requests: Vec<_> = registry.requests.iter().collect();
+        requests.sort_by(|a, b| a.0.cmp(b.0));
+
+        out.push_str("# HELP sunflower_http_requests_total Total HTTP requests by route and status class.\n");
+        out.push_str("# TYPE sunflower_http_requests_total counter\n");
+        for &(&(ref method, ref route, class), count) in requests.iter() {
+            writeln!(out, "sunflower_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                escape(method), escape(route), class, count).unwrap();
+        }
+
+        let mut durations: Vec<_> = registry.durations.iter().collect();
+        durations.sort_by(|a, b| a.0.cmp(b.0));
+
+        out.push_str("# HELP sunflower_http_request_duration_seconds HTTP request latency by route.\n");
+        out.push_str("# TYPE sunflower_http_request_duration_seconds histogram\n");
+        for &(&(ref method, ref route), histogram) in durations.iter() {
+            let labels = format!("method=\"{}\",route=\"{}\"", escape(method), escape(route));
+
+            for (le, count) in self.buckets.iter().zip(histogram.counts.iter()) {
+                writeln!(out, "sunflower_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, le, count).unwrap();
+            }
+
+            writeln!(out, "sunflower_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, histogram.count).unwrap();
+            writeln!(out, "sunflower_http_request_duration_seconds_sum{{{}}} {}", labels, histogram.sum).unwrap();
+            writeln!(out, "sunflower_http_request_duration_seconds_count{{{}}} {}", labels, histogram.count).unwrap();
+        }
+
+        if let Some(stats) = stats {
+            render_stats(&mut out, stats);
+        }
+
+        out
+    }
+}
+
+fn render_stats(out: &mut String, stats: &StatsSnapshot) {
+    let metric = |out: &mut String, name: &str, kind: &str, help: &str, value: f64| {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+        writeln!(out, "{} {}", name, value).unwrap();
+    };
+
+    metric(out, "sunflower_open_connections", "gauge", "Currently open connections.", stats.open_connections as f64);
+    metric(out, "sunflower_connections_accepted_total", "counter", "Total accepted connections.", stats.accepted as f64);
+    metric(out, "sunflower_accepts_per_second", "gauge", "Recent connection accept rate.", stats.accepts_per_sec);
+    metric(out, "sunflower_requests_total", "counter", "Total requests handled by the server.", stats.requests as f64);
+    metric(out, "sunflower_received_bytes_total", "counter", "Total bytes read from connections.", stats.bytes_in as f64);
+    metric(out, "sunflower_sent_bytes_total", "counter", "Total bytes written to connections.", stats.bytes_out as f64);
+    metric(out, "sunflower_pool_queued_tasks", "gauge", "Tasks waiting in the thread pool queue.", stats.pool_queued as f64);
+    metric(out, "sunflower_pool_threads", "gauge", "Live thread pool threads.", stats.pool_active as f64);
+    metric(out, "sunflower_pool_waiting_threads", "gauge", "Idle thread pool threads.", stats.pool_waiting as f64);
+    metric(out, "sunflower_pool_rejected_total", "counter", "Requests rejected because the thread pool was overloaded.", stats.pool_rejected as f64);
+    metric(out, "sunflower_panics_total", "counter", "Requests whose handler panicked.", stats.panics as f64);
+    metric(out, "sunflower_loop_iterations_total", "counter", "Event loop iterations.", stats.loop_iterations as f64);
+    metric(out, "sunflower_loop_latency_avg_seconds", "gauge", "Average event loop iteration time.", stats.loop_latency_avg.as_secs_f64());
+    metric(out, "sunflower_loop_latency_max_seconds", "gauge", "Maximum event loop iteration time.", stats.loop_latency_max.as_secs_f64());
+
+    let mut errors: Vec<_> = stats.errors.iter().map(|(kind, count)| (format!("{:?}", kind), *count)).collect();
+    errors.sort();
+
+    out.push_str("# HELP sunflower_errors_total Connection and accept errors by kind.\n");
+    out.push_str("# TYPE sunflower_errors_total counter\n");
+    for (kind, count) in errors {
+        writeln!(out, "sunflower_errors_total{{kind=\"{}\"}} {}", escape(&kind), count).unwrap();
+    }
+}
+
+fn status_class(code: u16) -> &'static str {
+    match code / 100 {
+        1 => "1xx",
+        2 => "2xx",
+        3 => "3xx",
+        4 => "4xx",
+        _ => "5xx",
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+
+/// begin 中间件，记录请求开始时间
+pub fn start(context: &mut Context) {
+    context.contexts.insert(START.to_owned(), Value::Instant(Instant::now()));
+}
@@ -1,27 +1,79 @@
-use server::Server;
+use server::{Server, Dispatch, Hooks, ShutdownHandle};
+use connection::{ConnInfo, CloseReason};
+use std::io;
+use std::mem;
+use std::collections::HashMap;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Duration;
 use stream_data::StreamData;
 use std::sync::{Arc, Mutex};
-use http::{Http, Request, Response};
-use error::MioResult;
+use http::{Http, Request, Response, Method};
+use httparse;
+use error::{MioResult, MioError};
 use self::context::{Context, Value};
 use self::middleware::Middleware;
-use self::group::Group;
 use self::route::Route;
+use self::router::Router;
+use self::schedule::{Schedule, Plan};
+use connection::ConnWriter;
+use util::timer::Timer;
+use util::cron::Cron;
+use util::sync::lock;
+use util::buffer_pool::BufferPool;
+use util::threadpool::{CoDel, PoolHandle, Scheduler, Priority};
+use stats::ServerStats;
+use executor::Spawner;
+#[cfg(feature = "h2c")]
+use http2::{self, Session, Responder};
 
 mod context;
 mod middleware;
 mod group;
 mod route;
+mod deferred;
+mod future;
+mod metrics;
+mod schedule;
+mod pattern;
+mod router;
+
+pub use self::deferred::Deferred;
+pub use self::future::async_handle;
+pub use self::metrics::Metrics;
+pub use self::schedule::JobHandle;
+pub use self::group::Group;
 
 pub type Handle = Fn(&mut Context) + Send + Sync + 'static;
 
+/// 处理函数 panic 时调用，参数是请求和 panic 信息
+pub type PanicHook = dyn Fn(&Request, &str) + Send + Sync + 'static;
+
 pub struct App {
     groups: Vec<Group>,
+    router: Router,
     begin: Vec<Middleware>,
     before: Vec<Middleware>,
     after: Vec<Middleware>,
     finish: Vec<Middleware>,
     not_found: Option<Middleware>,
+    timer: Arc<Timer>,
+    schedule: Schedule,
+    spawner: Option<Spawner>,
+    pool: Option<PoolHandle>,
+    inline: bool,
+    buffers: BufferPool,
+    hooks: Hooks,
+    idle_timeout: Option<Duration>,
+    drain_timeout: Option<Duration>,
+    shutdown: ShutdownHandle,
+    stats: ServerStats,
+    scheduler: Scheduler,
+    max_queue: Option<usize>,
+    reserved: Vec<(Priority, usize)>,
+    codel: Option<CoDel>,
+    retry_after: u32,
+    on_panic: Option<Box<PanicHook>>,
 }
 
 impl App {
@@ -29,94 +81,734 @@ impl App {
     pub fn new() -> App {
         App{
             groups: vec![Group::new("")],
+            router: Router::default(),
             begin: Vec::new(),
             before: Vec::new(),
             after: Vec::new(),
             finish: Vec::new(),
             not_found: None,
+            timer: Arc::new(Timer::new()),
+            schedule: Schedule::new(),
+            spawner: None,
+            pool: None,
+            inline: false,
+            buffers: BufferPool::new(),
+            hooks: Hooks::default(),
+            idle_timeout: None,
+            drain_timeout: None,
+            shutdown: ShutdownHandle::new(),
+            stats: ServerStats::new(),
+            scheduler: Scheduler::Shared,
+            max_queue: None,
+            reserved: Vec::new(),
+            codel: None,
+            retry_after: 1,
+            on_panic: None,
         }
     }
 
-    fn add<H>(&mut self, method: &str, pattern: &str, handle: H) -> &mut Route
+    //路径模式写错或者和已经注册的路由冲突时返回错误，路由不会被添加
+    fn add<H>(&mut self, methods: Vec<Method>, pattern: &str, handle: H) -> MioResult<&mut Route>
         where H: Fn(&mut Context) + Send + Sync + 'static
     {
         let route = Route::new(
-            method.parse().unwrap(),
+            methods,
             pattern.into(),
             Box::new(handle),
-        );
+        )?;
+
+        self.groups[0].routes.push(route);
+        let index = self.groups[0].routes.len() - 1;
+
+        if let Err(err) = self.router.add(&self.groups, &[0], index) {
+            self.groups[0].routes.pop();
+            return Err(err)
+        }
+
+        Ok(&mut self.groups[0].routes[index])
+    }
 
-        self.groups.get_mut(0).unwrap().routes.push(route);
-        self.groups.get_mut(0).unwrap().routes.last_mut().unwrap()
+    pub fn get<H>(&mut self, pattern: &str, handle: H) -> MioResult<&mut Route>
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.add(vec![Method::Get], pattern, handle)
     }
 
-    pub fn get<H>(&mut self, pattern: &str, handle: H) -> &mut Route
+    pub fn post<H>(&mut self, pattern: &str, handle: H) -> MioResult<&mut Route>
         where H: Fn(&mut Context) + Send + Sync + 'static
     {
-        self.add(&stringify!(get).to_uppercase(), pattern, handle)
+        self.add(vec![Method::Post], pattern, handle)
     }
 
+    pub fn put<H>(&mut self, pattern: &str, handle: H) -> MioResult<&mut Route>
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.add(vec![Method::Put], pattern, handle)
+    }
+
+    pub fn delete<H>(&mut self, pattern: &str, handle: H) -> MioResult<&mut Route>
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.add(vec![Method::Delete], pattern, handle)
+    }
+
+    pub fn patch<H>(&mut self, pattern: &str, handle: H) -> MioResult<&mut Route>
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.add(vec![Method::Patch], pattern, handle)
+    }
+
+    pub fn head<H>(&mut self, pattern: &str, handle: H) -> MioResult<&mut Route>
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.add(vec![Method::Head], pattern, handle)
+    }
+
+    pub fn options<H>(&mut self, pattern: &str, handle: H) -> MioResult<&mut Route>
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.add(vec![Method::Options], pattern, handle)
+    }
+
+    /// 匹配所有方法，同一路径上注册了具体方法的路由优先
+    pub fn any<H>(&mut self, pattern: &str, handle: H) -> MioResult<&mut Route>
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.add(Vec::new(), pattern, handle)
+    }
+
+    /// 一个处理函数注册多个方法，也可以用 `Method::NonStandard` 注册 PROPFIND 之类的扩展方法
+    pub fn route<H>(&mut self, methods: &[Method], pattern: &str, handle: H) -> MioResult<&mut Route>
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.add(methods.to_vec(), pattern, handle)
+    }
+
+    /// 添加路由分组，比如 `app.group("/api", |api| { api.get("/users", ...).unwrap(); })`
+    ///
+    /// 分组里可以再用 `Group::group` 嵌套，前缀依次拼接，外层分组的中间件作用于内层的路由。
+    /// 分组里的路由和已有路由或者彼此冲突时返回错误，整个分组都不会被添加。
+    pub fn group<F>(&mut self, prefix: &str, f: F) -> MioResult<()>
+        where F: FnOnce(&mut Group)
+    {
+        let mut group = Group::new(prefix);
+        f(&mut group);
+        self.groups.push(group);
+        let index = self.groups.len() - 1;
+
+        if let Err(err) = self.router.add_group(&self.groups, &[index]) {
+            self.groups.pop();
+            return Err(err)
+        }
 
-    pub fn run(self, url: &str) -> MioResult<()> {
-        let mut server: Server = Server::new(url)?;
-        server.run(Box::new(move |stream_data| {
-            self.handle(stream_data);
-        }))?;
         Ok(())
     }
 
-    pub fn handle(&self, stream_data: Arc<Mutex<StreamData>>) {
-        let mut http = Http::new(stream_data.clone());
+    /// 路由匹配之前执行，可以调用 stop 中止后面的处理
+    pub fn begin<H>(&mut self, handle: H)
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.begin.push(Middleware { inner: Box::new(handle) });
+    }
+
+    /// 匹配到路由后、处理函数之前执行
+    pub fn before_route<H>(&mut self, handle: H)
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.before.push(Middleware { inner: Box::new(handle) });
+    }
+
+    /// 处理函数之后执行
+    pub fn after_route<H>(&mut self, handle: H)
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.after.push(Middleware { inner: Box::new(handle) });
+    }
+
+    /// 最后执行，不管是否匹配到路由或被 stop；延迟响应时在响应完成前执行
+    pub fn finish<H>(&mut self, handle: H)
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.finish.push(Middleware { inner: Box::new(handle) });
+    }
+
+    /// 没有匹配的路由时执行，默认返回 404
+    pub fn not_found<H>(&mut self, handle: H)
+        where H: Fn(&mut Context) + Send + Sync + 'static
+    {
+        self.not_found = Some(Middleware { inner: Box::new(handle) });
+    }
+
+    /// 每隔 interval 在线程池里执行一次，run 之后开始计时；上一次还没执行完时跳过这一次
+    ///
+    /// interval 为 0 时返回错误，否则定时线程会一直空转。
+    pub fn every<F>(&mut self, interval: Duration, task: F) -> MioResult<JobHandle>
+        where F: Fn() + Send + Sync + 'static
+    {
+        if interval == Duration::from_secs(0) {
+            return Err(MioError::Error("interval of App::every must be greater than zero".to_owned()))
+        }
+
+        Ok(self.schedule.add(Plan::Every(interval), Box::new(task)))
+    }
+
+    /// run 之后过 delay 在线程池里执行一次
+    pub fn after<F>(&mut self, delay: Duration, task: F) -> JobHandle
+        where F: FnOnce() + Send + 'static
+    {
+        let task = Mutex::new(Some(task));
+
+        self.schedule.add(Plan::Once(delay), Box::new(move || {
+            if let Some(task) = lock(&task).take() {
+                task();
+            }
+        }))
+    }
+
+    /// 按 cron 表达式在线程池里执行，表达式格式见 `Cron`
+    pub fn cron<F>(&mut self, expr: &str, task: F) -> MioResult<JobHandle>
+        where F: Fn() + Send + Sync + 'static
+    {
+        let cron = Cron::parse(expr)?;
+        Ok(self.schedule.add(Plan::Cron(cron), Box::new(task)))
+    }
+
+    /// 所有路由都在事件循环线程上直接执行，适合处理都很快且不阻塞的场景
+    pub fn inline(&mut self, inline: bool) {
+        self.inline = inline;
+    }
+
+    /// 新连接建立时调用
+    pub fn on_accept<F>(&mut self, hook: F)
+        where F: Fn(&ConnInfo) + Send + Sync + 'static
+    {
+        self.hooks.on_accept = Some(Box::new(hook));
+    }
+
+    /// 连接关闭时调用，带关闭原因
+    pub fn on_close<F>(&mut self, hook: F)
+        where F: Fn(&ConnInfo, CloseReason) + Send + Sync + 'static
+    {
+        self.hooks.on_close = Some(Box::new(hook));
+    }
+
+    /// 连接读写出错时调用，之后还会调用 on_close
+    pub fn on_error<F>(&mut self, hook: F)
+        where F: Fn(&ConnInfo, &io::Error) + Send + Sync + 'static
+    {
+        self.hooks.on_error = Some(Box::new(hook));
+    }
+
+    /// 处理函数 panic 时调用，用来记录日志；panic 的请求返回 500
+    pub fn on_panic<F>(&mut self, hook: F)
+        where F: Fn(&Request, &str) + Send + Sync + 'static
+    {
+        self.on_panic = Some(Box::new(hook));
+    }
+
+    /// 连接空闲超时时间，默认不超时
+    pub fn idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = Some(timeout);
+    }
+
+    /// 线程池的调度方式，默认 Shared
+    ///
+    /// WorkStealing 只有每个 CPU 一个线程，只适合处理函数很短、不阻塞，而且主要在线程池里派生子任务的情况。
+    pub fn scheduler(&mut self, scheduler: Scheduler) {
+        self.scheduler = scheduler;
+    }
+
+    /// 线程池最多排队的请求数，超过后直接返回 503
+    pub fn max_queue(&mut self, max: usize) {
+        self.max_queue = Some(max);
+    }
+
+    /// 给 priority 和更高优先级的路由预留 workers 个线程，比如 reserve_workers(Priority::High, 2)
+    /// 保证慢请求占满线程池时健康检查还能执行；只对默认的 Shared 调度方式有效
+    pub fn reserve_workers(&mut self, priority: Priority, workers: usize) {
+        self.reserved.push((priority, workers));
+    }
+
+    /// 请求排队时间持续超过 target 达到 interval 后返回 503（CoDel）
+    pub fn queue_delay(&mut self, target: Duration, interval: Duration) {
+        self.codel = Some(CoDel {
+            target: target,
+            interval: interval,
+        });
+    }
+
+    /// 503 响应里的 Retry-After 秒数，默认 1
+    pub fn retry_after(&mut self, seconds: u32) {
+        self.retry_after = seconds;
+    }
+
+    /// 关闭时最多等这么久让正在处理和排队的请求完成，默认直接丢弃
+    pub fn drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = Some(timeout);
+    }
+
+    /// 关闭句柄，调用 shutdown 后 run 返回
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// 安装 Prometheus 指标，按路由记录请求并在 metrics 配置的路径上输出
+    ///
+    /// 输出路径和已有路由冲突时返回错误，不安装指标。
+    pub fn metrics(&mut self, metrics: Metrics) -> MioResult<()> {
+        let recorder = metrics.clone();
+        let stats = self.stats.clone();
+        let path = metrics.get_path().to_owned();
+
+        self.get(&path, move |context| {
+            let body = metrics.render(Some(&stats.snapshot()));
+            context.response.from_data("text/plain; version=0.0.4; charset=utf-8", body).unwrap();
+        })?;
+
+        self.begin(metrics::start);
+        self.finish(move |context| recorder.observe(context));
+
+        Ok(())
+    }
+
+    /// 服务器运行统计，可以在处理函数或其他线程里调用 snapshot
+    pub fn stats(&self) -> ServerStats {
+        self.stats.clone()
+    }
+
+    /// 连接缓冲区池，可以在运行期间查看统计
+    pub fn buffer_pool(&self) -> BufferPool {
+        self.buffers.clone()
+    }
+
+    pub fn run(mut self, url: &str) -> MioResult<()> {
+        let mut server: Server = Server::new(url)?;
+        server.set_scheduler(self.scheduler);
+        self.spawner = Some(server.spawner());
+        self.pool = Some(server.pool_handle());
+        server.set_buffer_pool(self.buffers.clone());
+        server.set_hooks(mem::replace(&mut self.hooks, Hooks::default()));
+        server.set_idle_timeout(self.idle_timeout);
+        server.set_drain_timeout(self.drain_timeout);
+        server.set_shutdown_handle(self.shutdown.clone());
+        server.set_stats(self.stats.clone());
+        server.set_max_queue(self.max_queue);
+
+        for &(priority, workers) in self.reserved.iter() {
+            server.set_reserved(priority, workers);
+        }
+        server.set_codel(self.codel);
+
+        let retry_after = self.retry_after;
+        server.set_reject(Box::new(move |stream_data| reject(stream_data, retry_after)));
+
+        //服务器退出后不再执行定时任务
+        let schedule = self.schedule.clone();
+        schedule.start(&self.timer, &server.pool_handle());
+
+        let app = Arc::new(self);
+
+        //所有请求都进线程池按普通优先级处理时，不用在事件循环上先解析一遍请求
+        if app.inline || needs_classify(&app.groups) {
+            let classify = app.clone();
+            server.set_classify(Box::new(move |stream_data| {
+                classify.classify(stream_data)
+            }));
+        }
+
+        let result = server.run(Box::new(move |stream_data| {
+            app.handle(stream_data);
+        }));
+
+        schedule.stop();
+        result
+    }
 
-        match http.decode() {
+    /// 只解析请求行，判断匹配的路由是否内联执行
+    pub fn classify(&self, stream_data: &StreamData) -> Dispatch {
+        if is_h2(stream_data) {
+            return Dispatch::Pool;
+        }
+
+        if self.inline {
+            return Dispatch::Inline;
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; 24];
+        let mut req = httparse::Request::new(&mut headers);
+
+        //请求行不完整或解析出错时交给线程池按原来的流程处理
+        if req.parse(&stream_data.reader).is_err() {
+            return Dispatch::Pool;
+        }
+
+        let (method, path) = match (req.method, req.path) {
+            (Some(method), Some(path)) => (method, path),
+            _ => return Dispatch::Pool,
+        };
+
+        let method: Method = match method.parse() {
+            Ok(method) => method,
+            Err(_) => return Dispatch::Pool,
+        };
+
+        match self.find(&method, path) {
+            Some((_, route, _)) if route.is_inline() => Dispatch::Inline,
+            Some((_, route, _)) => Dispatch::Priority(route.get_priority()),
+            _ => Dispatch::Pool,
+        }
+    }
+
+    /// 按方法和路径找到路由和它所在的从外到内的每层分组
+    fn find(&self, method: &Method, path: &str) -> Option<(Vec<&Group>, &Route, HashMap<String, String>)> {
+        let path = route_path(path);
+
+        self.router.find(method, &path).map(|(path, route, params)| {
+            let groups = router::resolve(&self.groups, path);
+            let route = &groups.last().unwrap().routes[route];
+            (groups, route, params)
+        })
+    }
+
+    /// 路径存在时允许的方法，OPTIONS 总是允许
+    fn allow(&self, path: &str) -> Option<String> {
+        let path = route_path(path);
+        let mut allowed: Vec<&str> = self.router.allowed(&path).iter().map(|method| method.as_str()).collect();
+
+        if allowed.is_empty() {
+            return None
+        }
+
+        //GET 的路由也处理 HEAD
+        if allowed.contains(&"GET") && !allowed.contains(&"HEAD") {
+            allowed.push("HEAD");
+        }
+
+        if !allowed.contains(&"OPTIONS") {
+            allowed.push("OPTIONS");
+        }
+
+        Some(allowed.join(", "))
+    }
+
+    /// HTTP/2 的每个流分别交给线程池处理，所以需要 Arc
+    pub fn handle(self: &Arc<Self>, stream_data: &mut StreamData) {
+        #[cfg(feature = "h2c")]
+        {
+            if is_h2(stream_data) {
+                let session = stream_data.h2.take().unwrap_or_else(Session::new);
+                let streams = session.process(stream_data);
+
+                stream_data.requests += streams.len();
+
+                for (request, responder) in streams {
+                    self.stream(request, responder);
+                }
+
+                //发出 GOAWAY 后写完就关闭连接
+                if session.is_closed() {
+                    stream_data.close();
+                }
+
+                stream_data.h2 = Some(session);
+                return;
+            }
+        }
+
+        let conn = stream_data.conn();
+
+        match Http::new(stream_data).decode() {
             Ok(Some(request)) => {
-                let mut context = Context::new(request);
-                let mut route_found = false;
-                if context.next() {
-                    for group in self.groups.iter() {
-                        for route in group.routes.iter() {
-                            if route.method() != &context.request.method {
-                                continue;
-                            }
-
-                            let path = {
-                                let path = context.request.path();
-                                let path = path.find('?').map_or(path.as_ref(), |pos| &path[..pos]);
-                                if path != "/" {
-                                    path.trim_right_matches('/').to_owned()
-                                } else {
-                                    path.to_owned()
-                                }
-                            };
-
-                            if path == route.pattern {
-                                route_found = true;
-                                route.execute(&mut context);
-                            }
-
-                            if !route_found {
-                                if let Some(ref not_found) = self.not_found {
-                                    not_found.execute(&mut context);
-                                } else {
-                                    context.response.status(404).from_text("Not Found").unwrap();
-                                }
-                            }
+                stream_data.requests += 1;
+
+                #[cfg(feature = "h2c")]
+                {
+                    if let Some(settings) = http2::upgrade_settings(&request) {
+                        let (session, responder) = Session::upgrade(stream_data, &request, &settings);
+
+                        match responder {
+                            Some(responder) => self.stream(request, responder),
+                            None => stream_data.close(),
                         }
+
+                        stream_data.h2 = Some(session);
+                        return;
                     }
                 }
-                http.encode(context.response);
+
+                let head = request.method == Method::Head;
+
+                //延迟响应由 Deferred 写回
+                if let Some(response) = self.dispatch(request, conn) {
+                    Http::new(stream_data).encode(response, head);
+                }
             }
             Ok(None) => {
                 let response = Response::empty(100);//100 - Continue 初始的请求已经接受，客户应当继续发送请求的其余部分
-                http.encode(response);
+                Http::new(stream_data).encode(response, false);
             }
             Err(err) => {
                 let response = Response::empty(501);
-                http.encode(response);
+                Http::new(stream_data).encode(response, false);
+            }
+        }
+    }
+
+    /// HTTP/2 的一个流交给线程池处理，慢的流不会挡住同一个连接上的其他流
+    #[cfg(feature = "h2c")]
+    fn stream(self: &Arc<Self>, request: Request, responder: Responder) {
+        let app = self.clone();
+
+        //延迟响应由 Deferred 通过 responder 写回
+        let respond = move || {
+            let mut context = Context::new(request);
+            context.set_responder(responder.clone());
+
+            if let Some(response) = app.execute(context) {
+                responder.send(response);
+            }
+        };
+
+        match self.pool {
+            Some(ref pool) => pool.execute(respond),
+            None => respond(),
+        }
+    }
+
+    /// 路由分发，处理函数延迟响应时返回 None
+    pub fn dispatch(&self, request: Request, conn: Option<ConnWriter>) -> Option<Response> {
+        let mut context = Context::new(request);
+        context.set_conn(conn);
+        self.execute(context)
+    }
+
+    fn execute(&self, mut context: Context) -> Option<Response> {
+        context.set_timer(self.timer.clone());
+        context.set_spawner(self.spawner.clone());
+        context.set_pool(self.pool.clone());
+
+        //处理函数 panic 时返回 500，finish 中间件照常执行
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.routing(&mut context)));
+
+        let panicked = match result {
+            Ok(_) => false,
+            Err(err) => {
+                self.stats.panicked();
+
+                if let Some(ref on_panic) = self.on_panic {
+                    on_panic(&context.request, panic_message(&err));
+                }
+
+                let mut response = Response::empty(500);
+                response.from_text("Internal Server Error").unwrap();
+                context.response = response;
+                true
+            }
+        };
+
+        //finish 总是执行，即使前面调用了 stop
+        for middleware in self.finish.iter() {
+            middleware.execute_always(&mut context);
+        }
+
+        match context.deferred().cloned() {
+            //已经延迟的请求通过 Deferred 返回 500，处理函数已经完成过时不会重复响应
+            Some(deferred) => {
+                if panicked {
+                    deferred.complete(context.response);
+                }
+                None
             }
+            None => Some(context.response)
+        }
+    }
+
+    //中间件和路由处理
+    fn routing(&self, context: &mut Context) {
+        for middleware in self.begin.iter() {
+            middleware.execute(context);
         }
+
+        if context.next() {
+            let found = self.find(&context.request.method, context.request.path());
+
+            match found {
+                Some((groups, route, params)) => {
+                    context.set_pattern(route.pattern.clone());
+                    context.request.params().extend(params);
+
+                    //外层分组的中间件先执行，after_route 按相反的顺序
+                    for middleware in self.before.iter().chain(groups.iter().flat_map(|group| group.before.iter())) {
+                        middleware.execute(context);
+                    }
+
+                    route.execute(context);
+
+                    for middleware in groups.iter().rev().flat_map(|group| group.after.iter()).chain(self.after.iter()) {
+                        middleware.execute(context);
+                    }
+                }
+                None => {
+                    //路径存在但方法不对时返回 405，OPTIONS 没有单独注册时自动回答
+                    if let Some(allow) = self.allow(context.request.path()) {
+                        if context.request.method == Method::Options {
+                            context.response.status(204).header(("Allow", &allow as &str));
+                        } else {
+                            context.response.status(405).header(("Allow", &allow as &str)).from_text("Method Not Allowed").unwrap();
+                        }
+                    } else if let Some(ref not_found) = self.not_found {
+                        not_found.execute(context);
+                    } else {
+                        context.response.status(404).from_text("Not Found").unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+}
+
+/// 连接已经升级到 HTTP/2，或者读缓冲区以连接前言开头
+#[cfg(feature = "h2c")]
+fn is_h2(stream_data: &StreamData) -> bool {
+    stream_data.h2.is_some() || http2::is_preface(&stream_data.reader)
+}
+
+#[cfg(not(feature = "h2c"))]
+fn is_h2(_stream_data: &StreamData) -> bool {
+    false
+}
+
+/// 有内联执行或者不是普通优先级的路由
+fn needs_classify(groups: &[Group]) -> bool {
+    groups.iter().any(|group| {
+        group.routes.iter().any(|route| route.is_inline() || route.get_priority() != Priority::Normal) || needs_classify(&group.groups)
+    })
+}
+
+/// 去掉查询参数和末尾的 /
+fn route_path(path: &str) -> String {
+    let path = path.find('?').map_or(path, |pos| &path[..pos]);
+    if path != "/" {
+        path.trim_end_matches('/').to_owned()
+    } else {
+        path.to_owned()
+    }
+}
+
+fn panic_message(err: &Box<dyn Any + Send>) -> &str {
+    match err.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => err.downcast_ref::<String>().map_or("Box<Any>", |message| message.as_str()),
     }
+}
+
+/// 线程池过载时在事件循环线程上直接返回 503，请求可能没读完，写完后关闭连接
+fn reject(stream_data: &mut StreamData, retry_after: u32) {
+    if is_h2(stream_data) {
+        stream_data.close();
+        return;
+    }
+
+    let mut response = Response::empty(503);
+    response.header(("Retry-After", &retry_after.to_string() as &str));
+    response.header(("Connection", "close"));
+    response.from_text("Service Unavailable").unwrap();
 
+    //请求还没解析，按请求行的开头判断是不是 HEAD
+    let head = stream_data.reader.starts_with(b"HEAD ");
 
+    stream_data.reader.clear();
+    Http::new(stream_data).encode(response, head);
+    stream_data.close();
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use buffer::Buffer;
+    use http;
+
+    //分发请求并按 HTTP/1.1 写出完整的响应
+    fn respond(app: &App, method: Method, path: &str) -> String {
+        let head = method == Method::Head;
+        let request = Request::new(method, path.to_owned(), HashMap::new(), "127.0.0.1:1".parse().unwrap(), Vec::new());
+        let response = app.dispatch(request, None).unwrap();
+
+        let mut writer = Buffer::new();
+        http::write_response(&mut writer, response, head);
+        String::from_utf8(writer.to_vec()).unwrap()
+    }
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.get("/a", |context| { context.response.from_text("a").unwrap(); }).unwrap();
+        app.post("/a", |context| { context.response.from_text("posted").unwrap(); }).unwrap();
+        app.put("/b", |_| {}).unwrap();
+        app
+    }
+
+    #[test]
+    fn method_not_allowed() {
+        let response = respond(&app(), Method::Delete, "/a");
+
+        assert!(response.starts_with("HTTP/1.1 405 "));
+        assert!(response.contains("\r\nAllow: GET, POST, HEAD, OPTIONS\r\n"));
+        assert!(response.contains("\r\nContent-Length: 18\r\n"));
+        assert!(response.ends_with("\r\n\r\nMethod Not Allowed"));
+
+        let response = respond(&app(), Method::Get, "/b");
+        assert!(response.starts_with("HTTP/1.1 405 "));
+        assert!(response.contains("\r\nAllow: PUT, OPTIONS\r\n"));
+
+        assert!(respond(&app(), Method::Get, "/missing").starts_with("HTTP/1.1 404 "));
+    }
+
+    #[test]
+    fn automatic_options() {
+        let response = respond(&app(), Method::Options, "/a");
+
+        assert!(response.starts_with("HTTP/1.1 204 "));
+        assert!(response.contains("\r\nAllow: GET, POST, HEAD, OPTIONS\r\n"));
+        assert!(!response.contains("Content-Length"));
+        assert!(response.ends_with("\r\n\r\n"));
+
+        assert!(respond(&app(), Method::Options, "/missing").starts_with("HTTP/1.1 404 "));
+    }
+
+    #[test]
+    fn registered_options_wins() {
+        let mut app = app();
+        app.options("/a", |context| { context.response.from_text("custom").unwrap(); }).unwrap();
+
+        let response = respond(&app, Method::Options, "/a");
+        assert!(response.starts_with("HTTP/1.1 200 "));
+        assert!(response.ends_with("custom"));
+    }
+
+    #[test]
+    fn head_uses_get_route_without_body() {
+        let response = respond(&app(), Method::Head, "/a");
+
+        assert!(response.starts_with("HTTP/1.1 200 "));
+        assert!(response.contains("\r\nContent-Length: 1\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn zero_interval_is_an_error() {
+        let mut app = App::new();
+
+        assert!(app.every(Duration::from_secs(0), || {}).is_err());
+        assert!(app.every(Duration::from_millis(1), || {}).is_ok());
+    }
+
+    #[test]
+    fn invalid_pattern_is_an_error() {
+        let mut app = App::new();
+
+        assert!(app.get("/a/*rest/b", |_| {}).is_err());
+        assert!(app.group("/api", |api| { assert!(api.get("/:", |_| {}).is_err()); }).is_ok());
+        assert!(app.get("/a/*rest", |_| {}).is_ok());
+    }
+}
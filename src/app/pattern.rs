@@ -0,0 +1,225 @@
+use regex::Regex;
+use url::percent_encoding::percent_decode;
+
+use error::{MioResult, MioError};
+
+pub enum Segment {
+    Static(String),
+    /// `:name`、`:name?`、`:name<regex>`
+    Param {
+        name: String,
+        constraint: Option<Regex>,
+        optional: bool,
+    },
+    /// `*name`，匹配剩下的所有段，只能放在最后
+    Wildcard(String),
+}
+
+/// 路由的路径模式
+///
+/// 按 `/` 分段，每段可以是固定字符串、参数 `:id`、可选参数 `:id?`、
+/// 带约束的参数 `:id<\d+>`，最后一段可以是通配符 `*path`。
+/// 由 `Router` 编译成前缀树，匹配到的值按段做百分号解码后放进 `Request::params`。
+pub struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> MioResult<Pattern> {
+        let mut segments = Vec::new();
+        let parts = split(pattern);
+
+        for (index, part) in parts.iter().enumerate() {
+            let segment = if part.starts_with(':') {
+                parse_param(pattern, &part[1..])?
+            } else if part.starts_with('*') {
+                if index + 1 != parts.len() {
+                    return Err(invalid(pattern, "wildcard must be the last segment"))
+                }
+
+                let name = if part.len() == 1 { "*" } else { &part[1..] };
+                Segment::Wildcard(name.to_owned())
+            } else {
+                Segment::Static(part.to_string())
+            };
+
+            segments.push(segment);
+        }
+
+        Ok(Pattern {
+            segments: segments,
+        })
+    }
+
+    /// 展开可选参数后的所有形式，先列出包含可选参数的形式
+    pub fn variants(&self) -> Vec<Vec<&Segment>> {
+        let mut variants: Vec<Vec<&Segment>> = vec![Vec::new()];
+
+        for segment in self.segments.iter() {
+            let optional = match *segment {
+                Segment::Param { optional, .. } => optional,
+                _ => false,
+            };
+
+            let mut next = Vec::new();
+
+            for variant in variants.iter() {
+                let mut with = variant.clone();
+                with.push(segment);
+                next.push(with);
+
+                if optional {
+                    next.push(variant.clone());
+                }
+            }
+
+            variants = next;
+        }
+
+        variants
+    }
+}
+
+pub fn split(path: &str) -> Vec<&str> {
+    let path = path.trim_matches('/');
+
+    if path.is_empty() {
+        Vec::new()
+    } else {
+        path.split('/').collect()
+    }
+}
+
+fn parse_param(pattern: &str, param: &str) -> MioResult<Segment> {
+    let (param, optional) = if param.ends_with('?') {
+        (&param[..param.len() - 1], true)
+    } else {
+        (param, false)
+    };
+
+    let (name, constraint) = match param.find('<') {
+        Some(pos) => {
+            if !param.ends_with('>') {
+                return Err(invalid(pattern, "unclosed constraint"))
+            }
+
+            //整段都要匹配
+            let regex = format!("^(?:{})$", &param[pos + 1..param.len() - 1]);
+            let regex = Regex::new(&regex).map_err(|err| invalid(pattern, &err.to_string()))?;
+
+            (&param[..pos], Some(regex))
+        }
+        None => (param, None),
+    };
+
+    if name.is_empty() {
+        return Err(invalid(pattern, "missing parameter name"))
+    }
+
+    Ok(Segment::Param {
+        name: name.to_owned(),
+        constraint: constraint,
+        optional: optional,
+    })
+}
+
+pub fn decode(part: &str) -> String {
+    percent_decode(part.as_bytes()).decode_utf8_lossy().into_owned()
+}
+
+fn invalid(pattern: &str, reason: &str) -> MioError {
+    MioError::Error(format!("invalid route pattern \"{}\": {}", pattern, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //把段写成字符串方便比较，约束写在尖括号里
+    fn describe(segment: &Segment) -> String {
+        match *segment {
+            Segment::Static(ref value) => value.clone(),
+            Segment::Param { ref name, ref constraint, optional } => format!(
+                ":{}{}{}",
+                name,
+                constraint.as_ref().map_or(String::new(), |regex| format!("<{}>", regex.as_str())),
+                if optional { "?" } else { "" },
+            ),
+            Segment::Wildcard(ref name) => format!("*{}", name),
+        }
+    }
+
+    fn segments(pattern: &str) -> Vec<String> {
+        Pattern::parse(pattern).unwrap().segments.iter().map(describe).collect()
+    }
+
+    fn variants(pattern: &str) -> Vec<String> {
+        let pattern = Pattern::parse(pattern).unwrap();
+
+        pattern.variants().iter()
+            .map(|variant| variant.iter().map(|segment| describe(segment)).collect::<Vec<_>>().join("/"))
+            .collect()
+    }
+
+    #[test]
+    fn parse_segments() {
+        assert_eq!(segments("/"), Vec::<String>::new());
+        assert_eq!(segments("/users/:id"), vec!["users", ":id"]);
+        assert_eq!(segments("users/:id/"), vec!["users", ":id"]);
+        assert_eq!(segments("/users/:id?"), vec!["users", ":id?"]);
+        assert_eq!(segments(r"/users/:id<\d+>"), vec!["users", r":id<^(?:\d+)$>"]);
+        assert_eq!(segments(r"/users/:id<\d+>?"), vec!["users", r":id<^(?:\d+)$>?"]);
+        assert_eq!(segments("/files/*path"), vec!["files", "*path"]);
+        assert_eq!(segments("/files/*"), vec!["files", "**"]);
+    }
+
+    #[test]
+    fn constraint_matches_whole_segment() {
+        let pattern = Pattern::parse(r"/:id<\d+>").unwrap();
+
+        match pattern.segments[0] {
+            Segment::Param { constraint: Some(ref regex), .. } => {
+                assert!(regex.is_match("42"));
+                assert!(!regex.is_match("42a"));
+                assert!(!regex.is_match("a42"));
+            }
+            _ => panic!("expected a constrained parameter"),
+        }
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert!(Pattern::parse("/files/*path/more").is_err());
+        assert!(Pattern::parse(r"/users/:id<\d+").is_err());
+        assert!(Pattern::parse("/users/:").is_err());
+        assert!(Pattern::parse("/users/:?").is_err());
+        assert!(Pattern::parse("/users/:<a>").is_err());
+        assert!(Pattern::parse("/users/:id<(>").is_err());
+    }
+
+    #[test]
+    fn optional_variants() {
+        assert_eq!(variants("/users/:id"), vec!["users/:id"]);
+        assert_eq!(variants("/users/:id?"), vec!["users/:id?", "users"]);
+        assert_eq!(variants("/a/:x?/:y?"), vec!["a/:x?/:y?", "a/:x?", "a/:y?", "a"]);
+        assert_eq!(variants("/a/:x?/b"), vec!["a/:x?/b", "a/b"]);
+    }
+
+    #[test]
+    fn split_path() {
+        assert_eq!(split(""), Vec::<&str>::new());
+        assert_eq!(split("/"), Vec::<&str>::new());
+        assert_eq!(split("/a/b/"), vec!["a", "b"]);
+        assert_eq!(split("a//b"), vec!["a", "", "b"]);
+    }
+
+    #[test]
+    fn percent_decode() {
+        assert_eq!(decode("plain"), "plain");
+        assert_eq!(decode("a%20b"), "a b");
+        assert_eq!(decode("%E4%BD%A0%E5%A5%BD"), "你好");
+        assert_eq!(decode("a%2Fb"), "a/b");
+        assert_eq!(decode("a+b"), "a+b");
+        assert_eq!(decode("%FF"), "\u{FFFD}");
+    }
+}
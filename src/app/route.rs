@@ -1,30 +1,68 @@
 
 use http::Method;
+use error::MioResult;
 use super::Handle;
 use super::context::Context;
+use super::pattern::Pattern;
+use util::threadpool::Priority;
 
 pub struct Route {
     pub pattern: String,
-    pub method: Method,
+    /// 为空时匹配所有方法
+    pub methods: Vec<Method>,
     handle: Box<Handle>,
+    matcher: Pattern,
+    inline: bool,
+    priority: Priority,
 }
 
 impl Route {
-    pub fn new(method: Method, pattern: String, handle: Box<Handle>) -> Route {
-        let mut route = Route {
+    /// pattern 写错时返回错误
+    pub fn new(methods: Vec<Method>, pattern: String, handle: Box<Handle>) -> MioResult<Route> {
+        let matcher = Pattern::parse(&pattern)?;
+
+        let route = Route {
             pattern: pattern.clone(),
-            method: method,
+            methods: methods,
             handle: handle,
+            matcher: matcher,
+            inline: false,
+            priority: Priority::Normal,
         };
-        route
+
+        Ok(route)
     }
 
     pub fn pattern(&self) -> &String {
         &self.pattern
     }
 
-    pub fn method(&self) -> &Method {
-        &self.method
+    pub fn methods(&self) -> &[Method] {
+        &self.methods
+    }
+
+    pub fn compiled(&self) -> &Pattern {
+        &self.matcher
+    }
+
+    /// 在事件循环线程上直接执行，不经过线程池，处理函数里不能有阻塞操作
+    pub fn inline(&mut self) -> &mut Route {
+        self.inline = true;
+        self
+    }
+
+    pub fn is_inline(&self) -> bool {
+        self.inline
+    }
+
+    /// 线程池按优先级执行，比如健康检查设为 High，导出之类的慢请求设为 Low
+    pub fn priority(&mut self, priority: Priority) -> &mut Route {
+        self.priority = priority;
+        self
+    }
+
+    pub fn get_priority(&self) -> Priority {
+        self.priority
     }
 
     pub fn execute(&self, context: &mut Context) {
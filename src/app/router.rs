@@ -0,0 +1,637 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+
+use http::Method;
+use error::{MioResult, MioError};
+use super::group::Group;
+use super::route::Route;
+use super::pattern::{self, Segment};
+
+//路由在 App 里的位置（从外到内每层分组的序号和路由的序号），以及按顺序对应的参数名
+struct Leaf {
+    groups: Vec<usize>,
+    route: usize,
+    names: Vec<String>,
+}
+
+struct ParamNode {
+    constraint: Option<Regex>,
+    node: Node,
+}
+
+#[derive(Default)]
+struct Node {
+    statics: HashMap<String, Node>,
+    //有约束的参数排在没有约束的前面
+    params: Vec<ParamNode>,
+    wildcard: Option<Leaf>,
+    leaf: Option<Leaf>,
+}
+
+/// 按方法分开的路由前缀树，注册路由时就插入，冲突在注册时报告
+///
+/// 每层按路径的一段分支，同一层先匹配固定字符串，再匹配参数，最后匹配通配符，
+/// 匹配不下去时回退到下一种。查找的代价只和路径的段数有关，和路由数量无关。
+#[derive(Default)]
+pub struct Router {
+    trees: Vec<(Method, Node)>,
+    //any 注册的路由，具体方法的树里找不到时再找
+    any: Node,
+    //方法（any 为 None）和路径形状对应的路由，参数不区分名字和约束
+    shapes: HashMap<(Option<String>, String), (Vec<usize>, usize)>,
+}
+
+//一个路由按方法和可选参数展开后的一条路径
+struct Entry<'a> {
+    method: Option<&'a Method>,
+    segments: Vec<&'a Segment>,
+    groups: Vec<usize>,
+    route: usize,
+}
+
+impl Router {
+    /// 注册 path 指向的分组里的第 route 个路由
+    ///
+    /// 和已有路由的方法相同、路径形状相同时返回错误，不再让后注册的路由被悄悄覆盖。
+    /// 同一位置的参数不管约束是否相同都算同一形状，比如 `:id<\d+>` 和 `:name`。
+    pub fn add(&mut self, groups: &[Group], path: &[usize], route: usize) -> MioResult<()> {
+        let group = *resolve(groups, path).last().unwrap();
+        let mut entries = Vec::new();
+
+        expand(&group.routes[route], path, route, &mut entries);
+        self.commit(groups, entries)
+    }
+
+    /// 注册 path 指向的分组和它嵌套的分组里的所有路由，有冲突时一个都不注册
+    pub fn add_group(&mut self, groups: &[Group], path: &[usize]) -> MioResult<()> {
+        let group = *resolve(groups, path).last().unwrap();
+        let mut entries = Vec::new();
+
+        collect(group, &mut path.to_vec(), &mut entries);
+        self.commit(groups, entries)
+    }
+
+    //先检查所有的冲突再修改路由树，出错时路由树保持不变
+    fn commit(&mut self, groups: &[Group], entries: Vec<Entry>) -> MioResult<()> {
+        let mut keys = Vec::with_capacity(entries.len());
+
+        {
+            let mut added: HashMap<&(Option<String>, String), (&[usize], usize)> = HashMap::new();
+
+            for entry in entries.iter() {
+                keys.push((entry.method.map(|method| method.as_str().to_owned()), shape(&entry.segments)));
+            }
+
+            for (entry, key) in entries.iter().zip(keys.iter()) {
+                let existing = self.shapes.get(key)
+                    .map(|&(ref group, route)| (&group[..], route))
+                    .or_else(|| added.get(key).cloned());
+
+                if let Some((group, route)) = existing {
+                    //同一个路由展开可选参数后可能得到相同的形状
+                    if group == &entry.groups[..] && route == entry.route {
+                        continue;
+                    }
+
+                    let method = entry.method.map_or("ANY", |method| method.as_str());
+
+                    return Err(MioError::Error(format!(
+                        "route {} {} conflicts with {} {}",
+                        method,
+                        route_at(groups, &entry.groups, entry.route).pattern(),
+                        method,
+                        route_at(groups, group, route).pattern(),
+                    )))
+                }
+
+                added.insert(key, (&entry.groups, entry.route));
+            }
+        }
+
+        for (entry, key) in entries.into_iter().zip(keys.into_iter()) {
+            self.shapes.entry(key).or_insert_with(|| (entry.groups.clone(), entry.route));
+            self.insert(entry);
+        }
+
+        Ok(())
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        let Entry { method, segments, groups: group, route } = entry;
+
+        let mut node = match method {
+            Some(method) => {
+                let position = match self.trees.iter().position(|&(ref m, _)| m == method) {
+                    Some(position) => position,
+                    None => {
+                        self.trees.push((method.clone(), Node::default()));
+                        self.trees.len() - 1
+                    }
+                };
+
+                &mut self.trees[position].1
+            }
+            None => &mut self.any,
+        };
+        let mut names = Vec::new();
+
+        for segment in segments.iter() {
+            node = match **segment {
+                Segment::Static(ref value) => node.statics.entry(value.clone()).or_insert_with(Node::default),
+                Segment::Param { ref name, ref constraint, .. } => {
+                    names.push(name.clone());
+
+                    let source = constraint.as_ref().map(|regex| regex.as_str());
+                    let index = match node.params.iter().position(|param| param.constraint.as_ref().map(|regex| regex.as_str()) == source) {
+                        Some(index) => index,
+                        None => {
+                            let index = match source {
+                                Some(_) => node.params.iter().position(|param| param.constraint.is_none()).unwrap_or(node.params.len()),
+                                None => node.params.len(),
+                            };
+
+                            node.params.insert(index, ParamNode {
+                                constraint: constraint.clone(),
+                                node: Node::default(),
+                            });
+
+                            index
+                        }
+                    };
+
+                    &mut node.params[index].node
+                }
+                Segment::Wildcard(ref name) => {
+                    names.push(name.clone());
+                    return place(&mut node.wildcard, group, route, names)
+                }
+            };
+        }
+
+        place(&mut node.leaf, group, route, names)
+    }
+
+    /// 返回路由所在的每层分组和路由的序号，以及解码后的路径参数
+    pub fn find(&self, method: &Method, path: &str) -> Option<(&[usize], usize, HashMap<String, String>)> {
+        let parts = pattern::split(path);
+
+        let found = self.tree(method).and_then(|node| find_in(node, &parts));
+
+        //没有单独注册 HEAD 时交给 GET 的路由处理
+        let found = match *method {
+            Method::Head => found.or_else(|| self.tree(&Method::Get).and_then(|node| find_in(node, &parts))),
+            _ => found,
+        };
+
+        found.or_else(|| find_in(&self.any, &parts))
+    }
+
+    fn tree(&self, method: &Method) -> Option<&Node> {
+        self.trees.iter().find(|&&(ref m, _)| m == method).map(|&(_, ref node)| node)
+    }
+
+    /// 路径存在时注册过的方法，用来返回 405 和 Allow 头部
+    pub fn allowed(&self, path: &str) -> Vec<&Method> {
+        let parts = pattern::split(path);
+
+        self.trees.iter()
+            .filter(|&&(_, ref node)| find_in(node, &parts).is_some())
+            .map(|&(ref method, _)| method)
+            .collect()
+    }
+}
+
+fn find_in<'a>(node: &'a Node, parts: &[&str]) -> Option<(&'a [usize], usize, HashMap<String, String>)> {
+    let mut values = Vec::new();
+
+    lookup(node, parts, &mut values).map(|leaf| {
+        let params = leaf.names.iter().cloned().zip(values.into_iter()).collect();
+        (&leaf.groups[..], leaf.route, params)
+    })
+}
+
+/// 按序号从外到内找到每层分组
+pub fn resolve<'a>(groups: &'a [Group], path: &[usize]) -> Vec<&'a Group> {
+    let mut chain: Vec<&Group> = Vec::with_capacity(path.len());
+
+    for &index in path.iter() {
+        let group = match chain.last() {
+            Some(parent) => &parent.groups[index],
+            None => &groups[index],
+        };
+
+        chain.push(group);
+    }
+
+    chain
+}
+
+fn route_at<'a>(groups: &'a [Group], path: &[usize], route: usize) -> &'a Route {
+    &resolve(groups, path).last().unwrap().routes[route]
+}
+
+//冲突已经在 commit 里检查过，这里只会遇到同一个路由展开后相同的形状，保留先展开的
+fn place(slot: &mut Option<Leaf>, group: Vec<usize>, route: usize, names: Vec<String>) {
+    if slot.is_some() {
+        return;
+    }
+
+    *slot = Some(Leaf {
+        groups: group,
+        route: route,
+        names: names,
+    });
+}
+
+fn expand<'a>(route: &'a Route, path: &[usize], index: usize, entries: &mut Vec<Entry<'a>>) {
+    for variant in route.compiled().variants() {
+        if route.methods().is_empty() {
+            entries.push(Entry {
+                method: None,
+                segments: variant.clone(),
+                groups: path.to_vec(),
+                route: index,
+            });
+        }
+
+        for method in route.methods().iter() {
+            entries.push(Entry {
+                method: Some(method),
+                segments: variant.clone(),
+                groups: path.to_vec(),
+                route: index,
+            });
+        }
+    }
+}
+
+fn collect<'a>(group: &'a Group, path: &mut Vec<usize>, entries: &mut Vec<Entry<'a>>) {
+    for (index, route) in group.routes.iter().enumerate() {
+        expand(route, path, index, entries);
+    }
+
+    for (index, child) in group.groups.iter().enumerate() {
+        path.push(index);
+        collect(child, path, entries);
+        path.pop();
+    }
+}
+
+//参数写成 :，通配符写成 *，固定的段不会以这两个字符开头
+fn shape(segments: &[&Segment]) -> String {
+    let mut shape = String::new();
+
+    for segment in segments.iter() {
+        shape.push('/');
+
+        match **segment {
+            Segment::Static(ref value) => shape.push_str(value),
+            Segment::Param { .. } => shape.push(':'),
+            Segment::Wildcard(_) => shape.push('*'),
+        }
+    }
+
+    shape
+}
+
+fn lookup<'a>(node: &'a Node, parts: &[&str], values: &mut Vec<String>) -> Option<&'a Leaf> {
+    let part = match parts.first() {
+        Some(part) => part,
+        None => {
+            if node.leaf.is_some() {
+                return node.leaf.as_ref()
+            }
+
+            //通配符也可以匹配空路径
+            return node.wildcard.as_ref().map(|leaf| {
+                values.push(String::new());
+                leaf
+            })
+        }
+    };
+
+    if let Some(child) = node.statics.get(*part) {
+        if let Some(leaf) = lookup(child, &parts[1..], values) {
+            return Some(leaf)
+        }
+    }
+
+    if !node.params.is_empty() {
+        let value = pattern::decode(part);
+
+        if !value.is_empty() {
+            for param in node.params.iter() {
+                if !param.constraint.as_ref().map_or(true, |regex| regex.is_match(&value)) {
+                    continue;
+                }
+
+                values.push(value.clone());
+
+                if let Some(leaf) = lookup(&param.node, &parts[1..], values) {
+                    return Some(leaf)
+                }
+
+                values.pop();
+            }
+        }
+    }
+
+    node.wildcard.as_ref().map(|leaf| {
+        let rest: Vec<String> = parts.iter().map(|part| pattern::decode(part)).collect();
+        values.push(rest.join("/"));
+        leaf
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Routes {
+        groups: Vec<Group>,
+        router: Router,
+    }
+
+    impl Routes {
+        fn new() -> Routes {
+            Routes {
+                groups: vec![Group::new("/")],
+                router: Router::default(),
+            }
+        }
+
+        //methods 为空时相当于 any
+        fn add(&mut self, methods: &[Method], pattern: &str) -> MioResult<()> {
+            self.groups[0].route(methods, pattern, |_| {})?;
+            let index = self.groups[0].routes.len() - 1;
+
+            let result = self.router.add(&self.groups, &[0], index);
+
+            if result.is_err() {
+                self.groups[0].routes.pop();
+            }
+
+            result
+        }
+
+        fn get(&mut self, pattern: &str) {
+            self.add(&[Method::Get], pattern).unwrap();
+        }
+
+        fn group<F>(&mut self, prefix: &str, f: F) -> MioResult<()>
+            where F: FnOnce(&mut Group)
+        {
+            let mut group = Group::new(prefix);
+            f(&mut group);
+            self.groups.push(group);
+            let index = self.groups.len() - 1;
+
+            let result = self.router.add_group(&self.groups, &[index]);
+
+            if result.is_err() {
+                self.groups.pop();
+            }
+
+            result
+        }
+
+        //返回匹配到的路由的模式和按名字排序的参数
+        fn find(&self, method: Method, path: &str) -> Option<(String, Vec<(String, String)>)> {
+            self.router.find(&method, path).map(|(groups, route, params)| {
+                let mut params: Vec<(String, String)> = params.into_iter().collect();
+                params.sort();
+
+                (route_at(&self.groups, groups, route).pattern().clone(), params)
+            })
+        }
+
+        fn pattern(&self, path: &str) -> Option<String> {
+            self.find(Method::Get, path).map(|(pattern, _)| pattern)
+        }
+
+        fn params(&self, path: &str) -> Vec<(String, String)> {
+            self.find(Method::Get, path).unwrap().1
+        }
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(name, value)| (name.to_owned(), value.to_owned())).collect()
+    }
+
+    #[test]
+    fn static_routes() {
+        let mut routes = Routes::new();
+        routes.get("/");
+        routes.get("/users");
+        routes.get("/users/list");
+
+        assert_eq!(routes.pattern("/"), Some("/".to_owned()));
+        assert_eq!(routes.pattern("/users"), Some("/users".to_owned()));
+        assert_eq!(routes.pattern("/users/"), Some("/users".to_owned()));
+        assert_eq!(routes.pattern("/users/list"), Some("/users/list".to_owned()));
+        assert_eq!(routes.pattern("/users/other"), None);
+        assert_eq!(routes.pattern("/missing"), None);
+    }
+
+    #[test]
+    fn params() {
+        let mut routes = Routes::new();
+        routes.get("/users/:id");
+        routes.get("/users/:id/posts/:post");
+
+        assert_eq!(routes.params("/users/42"), pairs(&[("id", "42")]));
+        assert_eq!(routes.params("/users/42/posts/7"), pairs(&[("id", "42"), ("post", "7")]));
+        assert_eq!(routes.pattern("/users"), None);
+        assert_eq!(routes.pattern("/users/42/posts"), None);
+        //空的段不匹配参数
+        assert_eq!(routes.pattern("/users//posts/7"), None);
+    }
+
+    #[test]
+    fn optional_params() {
+        let mut routes = Routes::new();
+        routes.get("/a/:x?/:y?");
+        routes.get("/users/:id/posts/:post?");
+
+        assert_eq!(routes.params("/a"), pairs(&[]));
+        assert_eq!(routes.params("/a/1"), pairs(&[("x", "1")]));
+        assert_eq!(routes.params("/a/1/2"), pairs(&[("x", "1"), ("y", "2")]));
+        assert_eq!(routes.pattern("/a/1/2/3"), None);
+        assert_eq!(routes.params("/users/1/posts"), pairs(&[("id", "1")]));
+        assert_eq!(routes.params("/users/1/posts/9"), pairs(&[("id", "1"), ("post", "9")]));
+    }
+
+    #[test]
+    fn constraints() {
+        let mut routes = Routes::new();
+        routes.get(r"/users/:id<\d+>");
+        routes.get(r"/files/:name<[a-z]+\.txt>");
+
+        assert_eq!(routes.params("/users/42"), pairs(&[("id", "42")]));
+        assert_eq!(routes.pattern("/users/bob"), None);
+        assert_eq!(routes.pattern("/users/42x"), None);
+        assert_eq!(routes.params("/files/readme.txt"), pairs(&[("name", "readme.txt")]));
+        assert_eq!(routes.pattern("/files/readme.md"), None);
+    }
+
+    #[test]
+    fn wildcards() {
+        let mut routes = Routes::new();
+        routes.get("/files/*path");
+        routes.get("/static/*");
+
+        assert_eq!(routes.params("/files/a"), pairs(&[("path", "a")]));
+        assert_eq!(routes.params("/files/a/b/c"), pairs(&[("path", "a/b/c")]));
+        assert_eq!(routes.params("/files"), pairs(&[("path", "")]));
+        assert_eq!(routes.params("/static/x/y"), pairs(&[("*", "x/y")]));
+    }
+
+    #[test]
+    fn percent_decoding() {
+        let mut routes = Routes::new();
+        routes.get("/users/:name");
+        routes.get(r"/ids/:id<\d+>");
+        routes.get("/files/*path");
+        routes.get("/a b");
+
+        assert_eq!(routes.params("/users/a%20b"), pairs(&[("name", "a b")]));
+        assert_eq!(routes.params("/users/%E4%BD%A0%E5%A5%BD"), pairs(&[("name", "你好")]));
+        //编码的斜杠还在同一段里
+        assert_eq!(routes.params("/users/a%2Fb"), pairs(&[("name", "a/b")]));
+        //约束匹配解码后的值
+        assert_eq!(routes.params("/ids/%34%32"), pairs(&[("id", "42")]));
+        assert_eq!(routes.params("/files/a%20b/c"), pairs(&[("path", "a b/c")]));
+        //固定的段按原样比较
+        assert_eq!(routes.pattern("/a%20b"), None);
+    }
+
+    #[test]
+    fn precedence() {
+        let mut routes = Routes::new();
+        routes.get("/users/me");
+        routes.get(r"/users/:id<\d+>/posts");
+        routes.get("/users/:name/profile");
+        routes.get("/users/*rest");
+
+        //固定字符串优先于参数，参数优先于通配符
+        assert_eq!(routes.pattern("/users/me"), Some("/users/me".to_owned()));
+        assert_eq!(routes.pattern("/users/42/posts"), Some(r"/users/:id<\d+>/posts".to_owned()));
+        assert_eq!(routes.pattern("/users/bob/profile"), Some("/users/:name/profile".to_owned()));
+        //有约束的参数匹配不下去时回退到没有约束的参数
+        assert_eq!(routes.pattern("/users/42/profile"), Some("/users/:name/profile".to_owned()));
+        //固定字符串匹配不下去时回退到参数
+        assert_eq!(routes.pattern("/users/me/profile"), Some("/users/:name/profile".to_owned()));
+        //都匹配不下去时回退到通配符
+        assert_eq!(routes.params("/users/bob/other"), pairs(&[("rest", "bob/other")]));
+        assert_eq!(routes.params("/users/me/posts"), pairs(&[("rest", "me/posts")]));
+    }
+
+    #[test]
+    fn methods() {
+        let mut routes = Routes::new();
+        routes.get("/page");
+        routes.add(&[Method::Post], "/page").unwrap();
+        routes.add(&[], "/page").unwrap();
+        routes.add(&[], "/other").unwrap();
+
+        assert_eq!(routes.find(Method::Get, "/page").map(|(pattern, _)| pattern), Some("/page".to_owned()));
+        assert_eq!(routes.find(Method::Put, "/other").map(|(pattern, _)| pattern), Some("/other".to_owned()));
+
+        let (_, route, _) = routes.router.find(&Method::Post, "/page").unwrap();
+        assert_eq!(routes.groups[0].routes[route].methods().len(), 1);
+        //没有具体方法的路由时交给 any
+        let (_, route, _) = routes.router.find(&Method::Put, "/page").unwrap();
+        assert!(routes.groups[0].routes[route].methods().is_empty());
+        //HEAD 没有单独注册时交给 GET
+        let (_, route, _) = routes.router.find(&Method::Head, "/page").unwrap();
+        assert_eq!(routes.groups[0].routes[route].methods()[0].as_str(), "GET");
+
+        let allowed: Vec<&str> = routes.router.allowed("/page").iter().map(|method| method.as_str()).collect();
+        assert_eq!(allowed, vec!["GET", "POST"]);
+        assert!(routes.router.allowed("/missing").is_empty());
+    }
+
+    #[test]
+    fn conflicts() {
+        let mut routes = Routes::new();
+        routes.get("/a");
+        routes.get("/users/:id");
+        routes.get("/files/*path");
+
+        assert!(routes.add(&[Method::Get], "/a").is_err());
+        assert!(routes.add(&[Method::Get], "/a/").is_err());
+        //参数名字和约束不同也算同一形状
+        assert!(routes.add(&[Method::Get], "/users/:name").is_err());
+        assert!(routes.add(&[Method::Get], r"/users/:id<\d+>").is_err());
+        assert!(routes.add(&[Method::Get], "/files/*rest").is_err());
+        //可选参数展开后的形状也要检查
+        assert!(routes.add(&[Method::Get], "/:page?/a").is_err());
+        //多个方法里有一个冲突时整个路由都不注册
+        assert!(routes.add(&[Method::Post, Method::Get], "/a").is_err());
+        assert_eq!(routes.find(Method::Post, "/a"), None);
+
+        //方法不同、形状不同都不冲突
+        routes.add(&[Method::Post], "/a").unwrap();
+        routes.add(&[], "/a").unwrap();
+        routes.get("/users/:id/posts");
+        routes.get("/users/me");
+        routes.get("/files/:name");
+        //同一个路由展开后相同的形状不算冲突
+        routes.get("/b/:x?/:y?");
+        routes.get(r"/c/:x<\d+>?/:y?");
+
+        assert_eq!(routes.params("/c/1"), pairs(&[("x", "1")]));
+        assert_eq!(routes.params("/c/z"), pairs(&[("y", "z")]));
+    }
+
+    #[test]
+    fn conflict_message() {
+        let mut routes = Routes::new();
+        routes.get(r"/users/:id<\d+>");
+
+        match routes.add(&[Method::Get], "/users/:name") {
+            Err(MioError::Error(message)) => assert_eq!(message, r"route GET /users/:name conflicts with GET /users/:id<\d+>"),
+            _ => panic!("expected a conflict"),
+        }
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        let mut routes = Routes::new();
+
+        assert!(routes.add(&[Method::Get], "/a/*rest/b").is_err());
+        assert!(routes.add(&[Method::Get], r"/users/:id<\d+").is_err());
+        assert!(routes.groups[0].routes.is_empty());
+        assert_eq!(routes.pattern("/a/x/b"), None);
+
+        let mut group = Group::new("/api");
+        assert!(group.get("/:", |_| {}).is_err());
+        assert!(group.routes.is_empty());
+    }
+
+    #[test]
+    fn group_conflicts() {
+        let mut routes = Routes::new();
+        routes.get("/api/users");
+
+        assert!(routes.group("/api", |api| { api.get("/users", |_| {}).unwrap(); }).is_err());
+        //分组内部冲突时整个分组都不注册
+        assert!(routes.group("/v1", |v1| {
+            v1.get("/a", |_| {}).unwrap();
+            v1.group("/b", |b| { b.get("/:id", |_| {}).unwrap(); });
+            v1.get("/b/:name", |_| {}).unwrap();
+        }).is_err());
+        assert_eq!(routes.pattern("/v1/a"), None);
+        assert_eq!(routes.groups.len(), 1);
+
+        routes.group("/v1", |v1| {
+            v1.get("/a", |_| {}).unwrap();
+            v1.group("/b", |b| { b.get("/:id", |_| {}).unwrap(); });
+        }).unwrap();
+
+        assert_eq!(routes.pattern("/v1/a"), Some("/v1/a".to_owned()));
+        assert_eq!(routes.params("/v1/b/7"), pairs(&[("id", "7")]));
+        assert!(routes.group("/", |root| { root.get("/v1/b/:other", |_| {}).unwrap(); }).is_err());
+    }
+}
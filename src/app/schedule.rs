@@ -0,0 +1,210 @@
+use std::cmp;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+
+use util::cron::Cron;
+use util::timer::{Timer, TimerHandle};
+use util::threadpool::PoolHandle;
+use util::sync::lock;
+
+pub type Task = dyn Fn() + Send + Sync + 'static;
+
+pub enum Plan {
+    Once(Duration),
+    Every(Duration),
+    Cron(Cron),
+}
+
+//一次到期的时间，cron 按本地时间接着往后算
+struct Fire {
+    at: Instant,
+    wall: DateTime<Local>,
+}
+
+impl Plan {
+    fn next(&self, previous: Option<&Fire>) -> Option<Fire> {
+        let now = Instant::now();
+
+        let at = match *self {
+            Plan::Once(delay) => {
+                if previous.is_some() {
+                    return None
+                }
+
+                now + delay
+            }
+            //按固定频率执行，落后时不补执行错过的次数
+            Plan::Every(interval) => {
+                let at = previous.map_or(now + interval, |previous| previous.at + interval);
+                cmp::max(at, now)
+            }
+            Plan::Cron(ref cron) => {
+                let after = previous.map_or_else(Local::now, |previous| cmp::max(previous.wall, Local::now()));
+                let next = cron.next_after(&after)?;
+                let delay = next.signed_duration_since(Local::now()).to_std().unwrap_or(Duration::from_secs(0));
+
+                return Some(Fire {
+                    at: now + delay,
+                    wall: next,
+                })
+            }
+        };
+
+        Some(Fire {
+            at: at,
+            wall: Local::now(),
+        })
+    }
+}
+
+struct Job {
+    plan: Plan,
+    task: Box<Task>,
+    cancelled: AtomicBool,
+    //上一次还没执行完时跳过这一次
+    running: AtomicBool,
+    timer: Mutex<Option<TimerHandle>>,
+}
+
+impl Job {
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+
+        if let Some(ref timer) = *lock(&self.timer) {
+            timer.cancel();
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+}
+
+//任务执行完，或者线程池关闭时没执行就被丢弃，都会清掉 running
+struct Running {
+    job: Arc<Job>,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.job.running.store(false, Ordering::Release);
+    }
+}
+
+/// 定时任务句柄，可以在任意线程取消
+#[derive(Clone)]
+pub struct JobHandle {
+    job: Arc<Job>,
+}
+
+impl JobHandle {
+    /// 取消之后不再执行，已经开始的这一次会执行完
+    pub fn cancel(&self) {
+        self.job.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.job.is_cancelled()
+    }
+
+    /// 是否正在线程池里执行
+    pub fn is_running(&self) -> bool {
+        self.job.running.load(Ordering::Acquire)
+    }
+}
+
+/// App 注册的定时任务，run 时开始计时，服务器退出时全部取消
+#[derive(Clone)]
+pub struct Schedule {
+    jobs: Vec<Arc<Job>>,
+}
+
+impl Schedule {
+    pub fn new() -> Schedule {
+        Schedule {
+            jobs: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, plan: Plan, task: Box<Task>) -> JobHandle {
+        let job = Arc::new(Job {
+            plan: plan,
+            task: task,
+            cancelled: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+            timer: Mutex::new(None),
+        });
+
+        self.jobs.push(job.clone());
+
+        JobHandle {
+            job: job,
+        }
+    }
+
+    /// 开始计时，到期后交给线程池执行；定时器只负责提交，不执行任务
+    pub fn start(&self, timer: &Arc<Timer>, pool: &PoolHandle) {
+        for job in self.jobs.iter() {
+            arm(job.clone(), Arc::downgrade(timer), pool.clone(), None);
+        }
+    }
+
+    pub fn stop(&self) {
+        for job in self.jobs.iter() {
+            job.cancel();
+        }
+    }
+}
+
+//定时器里只保存弱引用，App 退出后定时器可以正常释放
+fn arm(job: Arc<Job>, timer: Weak<Timer>, pool: PoolHandle, previous: Option<Fire>) {
+    if job.is_cancelled() {
+        return
+    }
+
+    let fire = match job.plan.next(previous.as_ref()) {
+        Some(fire) => fire,
+        None => return,
+    };
+
+    let at = fire.at;
+
+    let handle = match timer.upgrade() {
+        Some(strong) => {
+            let job = job.clone();
+
+            strong.schedule_at(at, move || {
+                if job.is_cancelled() {
+                    return
+                }
+
+                run(&job, &pool);
+                arm(job, timer, pool, Some(fire));
+            })
+        }
+        None => return,
+    };
+
+    *lock(&job.timer) = Some(handle.clone());
+
+    //设置句柄之前取消的，这里补上
+    if job.is_cancelled() {
+        handle.cancel();
+    }
+}
+
+fn run(job: &Arc<Job>, pool: &PoolHandle) {
+    if job.running.swap(true, Ordering::AcqRel) {
+        return
+    }
+
+    let running = Running { job: job.clone() };
+
+    pool.execute(move || {
+        let running = running;
+        (running.job.task)();
+    });
+}
@@ -0,0 +1,15 @@
+extern crate sunflower;
+
+use sunflower::codec::{Framed, LinesCodec, service_fn};
+
+fn main() {
+    let echo = service_fn(|line: String, _| {
+        if line.is_empty() {
+            None
+        } else {
+            Some(line)
+        }
+    });
+
+    Framed::new(LinesCodec::new(), echo).run("127.0.0.1:8888").unwrap();
+}
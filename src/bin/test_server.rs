@@ -7,9 +7,9 @@ fn main() {
     let mut app = App::new();
     app.get("/", |context| {
         context.response.status(200).from_text("我是root!").unwrap();
-    });
+    }).unwrap();
     app.get("/aaa", |context| {
         context.response.status(200).from_text("Hello world!").unwrap();
-    });
+    }).unwrap();
     app.run("127.0.0.1:8888").unwrap();
 }
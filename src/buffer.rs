@@ -0,0 +1,283 @@
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::ops::Deref;
+
+/// 带读游标的字节缓冲区
+///
+/// `consume` 只移动游标，是 O(1) 的；已消费的空间在追加数据时按需回收，
+/// 避免每次读取都把剩余数据拷贝到新的 Vec。
+#[derive(Debug, Default)]
+pub struct Buffer {
+    data: Vec<u8>,
+    pos: usize,
+    //解码器已经检查过的未消费字节数，相对游标
+    scanned: usize,
+}
+
+impl Buffer {
+    pub fn new() -> Buffer {
+        Buffer {
+            data: Vec::new(),
+            pos: 0,
+            scanned: 0,
+        }
+    }
+
+    pub fn with_capacity(capacity: usize) -> Buffer {
+        Buffer {
+            data: Vec::with_capacity(capacity),
+            pos: 0,
+            scanned: 0,
+        }
+    }
+
+    pub fn from_vec(data: Vec<u8>) -> Buffer {
+        Buffer {
+            data: data,
+            pos: 0,
+            scanned: 0,
+        }
+    }
+
+    /// 未消费的字节数
+    pub fn len(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.data.capacity()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[self.pos..]
+    }
+
+    /// 解码器上次检查到的位置，下次从这里继续找分隔符，不用每次从头扫描
+    pub fn scanned(&self) -> usize {
+        cmp::min(self.scanned, self.len())
+    }
+
+    pub fn set_scanned(&mut self, scanned: usize) {
+        self.scanned = cmp::min(scanned, self.len());
+    }
+
+    /// 丢弃前 n 个字节
+    pub fn consume(&mut self, n: usize) {
+        self.pos = cmp::min(self.pos + n, self.data.len());
+        self.scanned = self.scanned.saturating_sub(n);
+
+        if self.pos == self.data.len() {
+            self.clear();
+        }
+    }
+
+    /// 取出前 n 个字节
+    pub fn split_to(&mut self, n: usize) -> Vec<u8> {
+        let n = cmp::min(n, self.len());
+        let head = self.data[self.pos..self.pos + n].to_vec();
+        self.consume(n);
+        head
+    }
+
+    /// 取出全部未消费的数据，没有消费过时不拷贝
+    pub fn take_all(&mut self) -> Vec<u8> {
+        let data = mem::replace(&mut self.data, Vec::new());
+        let pos = mem::replace(&mut self.pos, 0);
+        self.scanned = 0;
+
+        if pos == 0 {
+            data
+        } else {
+            data[pos..].to_vec()
+        }
+    }
+
+    pub fn into_vec(mut self) -> Vec<u8> {
+        self.take_all()
+    }
+
+    /// 取出底层的 Vec，保留容量，用于还回缓冲区池
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// 从 reader 读一次，直接读进空闲空间，至少预留 reserve 个字节
+    pub fn read_from<R: Read>(&mut self, reader: &mut R, reserve: usize) -> io::Result<usize> {
+        self.reserve(reserve);
+
+        let len = self.data.len();
+        let spare = cmp::max(self.data.capacity() - len, reserve);
+        self.data.resize(len + spare, 0);
+
+        let result = reader.read(&mut self.data[len..]);
+
+        let size = *result.as_ref().unwrap_or(&0);
+        self.data.truncate(len + size);
+
+        result
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.pos = 0;
+        self.scanned = 0;
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        self.compact();
+        self.data.reserve(additional);
+    }
+
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.compact();
+        self.data.extend_from_slice(data);
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        self.compact();
+        self.data.push(byte);
+    }
+
+    //已消费的部分超过一半时才搬移，均摊下来每个字节只搬一次
+    fn compact(&mut self) {
+        if self.pos > 0 && self.pos >= self.data.len() / 2 {
+            self.data.drain(..self.pos);
+            self.pos = 0;
+        }
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl AsRef<[u8]> for Buffer {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl From<Vec<u8>> for Buffer {
+    fn from(data: Vec<u8>) -> Buffer {
+        Buffer::from_vec(data)
+    }
+}
+
+impl Read for Buffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let amt = cmp::min(buf.len(), self.len());
+
+        buf[..amt].copy_from_slice(&self.data[self.pos..self.pos + amt]);
+        self.consume(amt);
+
+        Ok(amt)
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consume_moves_cursor() {
+        let mut buffer = Buffer::from_vec(b"hello world".to_vec());
+
+        buffer.consume(6);
+        assert_eq!(buffer.as_slice(), b"world");
+        assert_eq!(buffer.len(), 5);
+        assert_eq!(buffer.pos, 6);
+
+        //超过长度时清空
+        buffer.consume(100);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.pos, 0);
+        assert_eq!(buffer.data.len(), 0);
+    }
+
+    #[test]
+    fn compact_after_half_consumed() {
+        let mut buffer = Buffer::from_vec(b"abcdef".to_vec());
+
+        buffer.consume(2);
+        buffer.push(b'g');
+        assert_eq!(buffer.pos, 2);
+        assert_eq!(&buffer[..], b"cdefg");
+
+        buffer.consume(2);
+        buffer.extend_from_slice(b"h");
+        assert_eq!(buffer.pos, 0);
+        assert_eq!(&buffer[..], b"efgh");
+    }
+
+    #[test]
+    fn split_and_take() {
+        let mut buffer = Buffer::from_vec(b"abcdef".to_vec());
+
+        assert_eq!(buffer.split_to(2), b"ab");
+        assert_eq!(buffer.split_to(100), b"cdef");
+        assert!(buffer.is_empty());
+
+        let data = b"abc".to_vec();
+        let ptr = data.as_ptr();
+        let mut buffer = Buffer::from_vec(data);
+
+        //没有消费过时直接取出底层的 Vec
+        let all = buffer.take_all();
+        assert_eq!(all.as_ptr(), ptr);
+        assert!(buffer.is_empty());
+
+        let mut buffer = Buffer::from_vec(b"abc".to_vec());
+        buffer.consume(1);
+        assert_eq!(buffer.into_vec(), b"bc");
+    }
+
+    #[test]
+    fn read_from_appends() {
+        let mut buffer = Buffer::from_vec(b"ab".to_vec());
+        let mut reader: &[u8] = b"cdef";
+
+        assert_eq!(buffer.read_from(&mut reader, 2).unwrap(), 4);
+        assert_eq!(&buffer[..], b"abcdef");
+        assert!(buffer.capacity() >= 6);
+
+        //读到结尾时不留下多余的字节
+        assert_eq!(buffer.read_from(&mut reader, 16).unwrap(), 0);
+        assert_eq!(&buffer[..], b"abcdef");
+    }
+
+    #[test]
+    fn read_and_write() {
+        let mut buffer = Buffer::new();
+        buffer.write_all(b"hello").unwrap();
+
+        let mut out = [0; 3];
+        assert_eq!(buffer.read(&mut out).unwrap(), 3);
+        assert_eq!(&out, b"hel");
+        assert_eq!(&buffer[..], b"lo");
+
+        let mut rest = Vec::new();
+        buffer.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"lo");
+        assert!(buffer.is_empty());
+    }
+}
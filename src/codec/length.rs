@@ -0,0 +1,107 @@
+use error::{MioResult, MioError};
+use buffer::Buffer;
+use super::Codec;
+
+/// 4 字节大端长度前缀分帧
+pub struct LengthCodec {
+    max_length: usize,
+}
+
+impl LengthCodec {
+    pub fn new() -> LengthCodec {
+        LengthCodec {
+            max_length: 8 * 1024 * 1024,
+        }
+    }
+
+    pub fn with_max_length(max_length: usize) -> LengthCodec {
+        LengthCodec {
+            max_length: max_length,
+        }
+    }
+}
+
+impl Codec for LengthCodec {
+    type In = Vec<u8>;
+    type Out = Vec<u8>;
+
+    fn decode(&self, buf: &mut Buffer) -> MioResult<Option<Vec<u8>>> {
+        if buf.len() < 4 {
+            return Ok(None)
+        }
+
+        let len = (buf[0] as usize) << 24 | (buf[1] as usize) << 16 | (buf[2] as usize) << 8 | buf[3] as usize;
+
+        if len > self.max_length {
+            return Err(MioError::Error("Frame too long".to_owned()))
+        }
+
+        if buf.len() - 4 < len {
+            return Ok(None)
+        }
+
+        buf.consume(4);
+        let frame = buf.split_to(len);
+
+        Ok(Some(frame))
+    }
+
+    fn encode(&self, item: Vec<u8>, buf: &mut Buffer) -> MioResult<()> {
+        let len = item.len();
+
+        if len > self.max_length || len > u32::max_value() as usize {
+            return Err(MioError::Error("Frame too long".to_owned()))
+        }
+
+        buf.extend_from_slice(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+        buf.extend_from_slice(&item);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let codec = LengthCodec::new();
+        let mut buf = Buffer::new();
+
+        codec.encode(b"hello".to_vec(), &mut buf).unwrap();
+        codec.encode(Vec::new(), &mut buf).unwrap();
+        assert_eq!(&buf[..9], &[0, 0, 0, 5, b'h', b'e', b'l', b'l', b'o']);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Vec::new()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn partial_frame() {
+        let codec = LengthCodec::new();
+        let mut buf = Buffer::from_vec(vec![0, 0, 1]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(&[2, 1, 2]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(buf.len(), 6);
+
+        buf.extend_from_slice(&[0; 256]);
+        assert_eq!(codec.decode(&mut buf).unwrap().map(|frame| frame.len()), Some(258));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn max_length() {
+        let codec = LengthCodec::with_max_length(4);
+
+        let mut buf = Buffer::from_vec(vec![0, 0, 0, 5]);
+        assert!(codec.decode(&mut buf).is_err());
+
+        assert!(codec.encode(vec![0; 5], &mut Buffer::new()).is_err());
+        assert!(codec.encode(vec![0; 4], &mut Buffer::new()).is_ok());
+    }
+}
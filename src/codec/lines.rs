@@ -0,0 +1,128 @@
+use error::{MioResult, MioError};
+use buffer::Buffer;
+use super::Codec;
+
+/// 按行分帧，去掉行尾的 \r\n，编码时补上 \n
+pub struct LinesCodec {
+    max_length: usize,
+}
+
+impl LinesCodec {
+    pub fn new() -> LinesCodec {
+        LinesCodec {
+            max_length: usize::max_value(),
+        }
+    }
+
+    pub fn with_max_length(max_length: usize) -> LinesCodec {
+        LinesCodec {
+            max_length: max_length,
+        }
+    }
+}
+
+impl Codec for LinesCodec {
+    type In = String;
+    type Out = String;
+
+    fn decode(&self, buf: &mut Buffer) -> MioResult<Option<String>> {
+        //上次没找到换行的部分不再扫描
+        let start = buf.scanned();
+
+        let pos = match buf[start..].iter().position(|b| *b == b'\n') {
+            Some(pos) => start + pos,
+            None => {
+                let len = buf.len();
+                buf.set_scanned(len);
+
+                if buf.len() > self.max_length {
+                    return Err(MioError::Error("Line too long".to_owned()))
+                }
+                return Ok(None)
+            }
+        };
+
+        if pos > self.max_length {
+            return Err(MioError::Error("Line too long".to_owned()))
+        }
+
+        let line = buf.split_to(pos + 1);
+        let line = String::from_utf8_lossy(&line[..pos]);
+
+        Ok(Some(line.trim_end_matches('\r').to_owned()))
+    }
+
+    fn encode(&self, item: String, buf: &mut Buffer) -> MioResult<()> {
+        buf.extend_from_slice(item.as_bytes());
+        buf.push(b'\n');
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(codec: &LinesCodec, buf: &mut Buffer) -> Option<String> {
+        codec.decode(buf).unwrap()
+    }
+
+    #[test]
+    fn decode_lines() {
+        let codec = LinesCodec::new();
+        let mut buf = Buffer::from_vec(b"one\r\ntwo\n\nthree".to_vec());
+
+        assert_eq!(decode(&codec, &mut buf), Some("one".to_owned()));
+        assert_eq!(decode(&codec, &mut buf), Some("two".to_owned()));
+        assert_eq!(decode(&codec, &mut buf), Some("".to_owned()));
+        assert_eq!(decode(&codec, &mut buf), None);
+        assert_eq!(&buf[..], b"three");
+    }
+
+    #[test]
+    fn partial_line_resumes_scan() {
+        let codec = LinesCodec::new();
+        let mut buf = Buffer::new();
+
+        buf.extend_from_slice(b"hel");
+        assert_eq!(decode(&codec, &mut buf), None);
+        assert_eq!(buf.scanned(), 3);
+
+        buf.extend_from_slice(b"lo");
+        assert_eq!(decode(&codec, &mut buf), None);
+        assert_eq!(buf.scanned(), 5);
+
+        buf.extend_from_slice(b"\nwor");
+        assert_eq!(decode(&codec, &mut buf), Some("hello".to_owned()));
+        assert_eq!(buf.scanned(), 0);
+
+        buf.extend_from_slice(b"ld\n");
+        assert_eq!(decode(&codec, &mut buf), Some("world".to_owned()));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn max_length() {
+        let codec = LinesCodec::with_max_length(4);
+
+        let mut buf = Buffer::from_vec(b"abcd\n".to_vec());
+        assert_eq!(decode(&codec, &mut buf), Some("abcd".to_owned()));
+
+        let mut buf = Buffer::from_vec(b"abcde\n".to_vec());
+        assert!(codec.decode(&mut buf).is_err());
+
+        //没有换行时也不会无限缓存
+        let mut buf = Buffer::from_vec(b"abcde".to_vec());
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn encode_appends_newline() {
+        let codec = LinesCodec::new();
+        let mut buf = Buffer::new();
+
+        codec.encode("a".to_owned(), &mut buf).unwrap();
+        codec.encode("b".to_owned(), &mut buf).unwrap();
+        assert_eq!(&buf[..], b"a\nb\n");
+    }
+}
@@ -0,0 +1,109 @@
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+
+use server::Server;
+use stream_data::StreamData;
+use buffer::Buffer;
+use error::MioResult;
+
+pub use self::lines::LinesCodec;
+pub use self::length::LengthCodec;
+
+mod lines;
+mod length;
+
+/// 帧编解码，在连接的读写缓冲区和消息之间转换
+///
+/// 同一个 codec 被所有连接共享，没收完的数据留在读缓冲区里，下次读到数据后再解码。
+pub trait Codec: Send + Sync + 'static {
+    type In;
+    type Out;
+
+    /// 解出一帧并把对应的字节从 buf 里移除，数据不够一帧时返回 Ok(None)
+    fn decode(&self, buf: &mut Buffer) -> MioResult<Option<Self::In>>;
+
+    fn encode(&self, item: Self::Out, buf: &mut Buffer) -> MioResult<()>;
+}
+
+/// 处理解出的消息，返回 None 表示单向消息不需要回复
+pub trait Service: Send + Sync + 'static {
+    type Request;
+    type Response;
+
+    fn call(&self, request: Self::Request, remote_addr: SocketAddr) -> Option<Self::Response>;
+}
+
+pub struct ServiceFn<F, Req, Res> {
+    f: F,
+    _marker: PhantomData<fn(Req) -> Res>,
+}
+
+pub fn service_fn<F, Req, Res>(f: F) -> ServiceFn<F, Req, Res>
+    where F: Fn(Req, SocketAddr) -> Option<Res> + Send + Sync + 'static
+{
+    ServiceFn {
+        f: f,
+        _marker: PhantomData,
+    }
+}
+
+impl<F, Req, Res> Service for ServiceFn<F, Req, Res>
+    where F: Fn(Req, SocketAddr) -> Option<Res> + Send + Sync + 'static, Req: 'static, Res: 'static
+{
+    type Request = Req;
+    type Response = Res;
+
+    fn call(&self, request: Req, remote_addr: SocketAddr) -> Option<Res> {
+        (self.f)(request, remote_addr)
+    }
+}
+
+/// 基于 Codec + Service 的通用协议服务
+pub struct Framed<C, S> {
+    codec: C,
+    service: S,
+}
+
+impl<C, S> Framed<C, S>
+    where C: Codec, S: Service<Request = C::In, Response = C::Out>
+{
+    pub fn new(codec: C, service: S) -> Framed<C, S> {
+        Framed {
+            codec: codec,
+            service: service,
+        }
+    }
+
+    pub fn run(self, url: &str) -> MioResult<()> {
+        let mut server: Server = Server::new(url)?;
+        server.run(Box::new(move |stream_data| {
+            self.handle(stream_data);
+        }))?;
+        Ok(())
+    }
+
+    pub fn handle(&self, stream_data: &mut StreamData) {
+        let remote_addr = stream_data.remote_addr();
+
+        loop {
+            let frame = match self.codec.decode(&mut stream_data.reader) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(_) => {
+                    //协议错误，写完已有的回复后关闭连接
+                    stream_data.close();
+                    break;
+                }
+            };
+
+            stream_data.requests += 1;
+
+            if let Some(response) = self.service.call(frame, remote_addr) {
+                if self.codec.encode(response, &mut stream_data.writer).is_err() {
+                    stream_data.close();
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -1,126 +1,338 @@
-use std::thread;
-use std::result::Result;
-use std::net::ToSocketAddrs;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::rc::Rc;
 use std::io::{self, Write};
 use std::io::ErrorKind::WouldBlock;
-use std::error::Error;
-use std::io::Read;
-use std::fmt::{self, Formatter};
 use std::convert::From;
-use std::net::Shutdown;
-use mio::net::{TcpListener, TcpStream};
-use mio::deprecated::TryRead;
-use mio::channel::{self, Receiver, Sender};
-use mio::{Token, Ready, PollOpt, Poll, Events, Event, Evented};
-use util::threadpool::Pool;
+use std::net::SocketAddr;
+use std::time::Instant;
+use std::panic::{self, AssertUnwindSafe};
+use mio::net::TcpStream;
+use mio::channel::Sender;
+use mio::{Token, Ready, PollOpt, Poll, Evented};
+use util::threadpool::{Pool, Priority};
 use stream_data::StreamData;
-use server::Handle;
+use buffer::Buffer;
+use util::buffer_pool::BufferPool;
+use stats::ServerStats;
+use server::{Handle, Classify, Dispatch, Reject};
+use error::{MioResult, MioError};
+
+//每次读取至少预留的空间
+const READ_SIZE: usize = 4096;
+//空闲时缓冲区容量超过这个值就换回小的
+const TRIM_SIZE: usize = 16 * 1024;
 
 pub enum ConnEvent {
-    Write(Token),
-    Read(Token),
+    /// 线程池处理完成，StreamData 连同待写的响应交还给连接
+    Done(Token, StreamData),
+    /// 其他线程追加的数据
+    Write(Token, Vec<u8>),
+    /// 关闭服务器
+    Shutdown,
+}
+
+/// 连接关闭的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// 客户端关闭了连接
+    Hup,
+    /// 服务端处理完后主动关闭，如 `StreamData::close`
+    Closed,
+    /// 空闲超时
+    Timeout,
+    /// 读写出错
+    Error,
+    /// 服务器关闭
+    Shutdown,
+}
+
+/// 连接信息，传给连接的生命周期回调
+#[derive(Debug, Clone)]
+pub struct ConnInfo {
+    /// 连接 id，同一个服务器内唯一
+    pub id: usize,
+    pub peer_addr: SocketAddr,
+    pub accepted_at: Instant,
+    /// 处理过的请求数
+    pub requests: usize,
+    pub bytes_read: usize,
+    pub bytes_written: usize,
+}
+
+/// 连接写句柄，可在任意线程向连接追加数据（如 SSE 推送）
+#[derive(Clone)]
+pub struct ConnWriter {
+    token: Token,
+    tx: Sender<ConnEvent>,
+    closed: Arc<AtomicBool>,
+}
+
+impl ConnWriter {
+    pub fn write(&self, data: &[u8]) -> MioResult<()> {
+        self.send(data.to_vec())
+    }
+
+    /// 数据的所有权直接交给事件循环，一次发送的数据保证连续
+    pub fn send(&self, data: Vec<u8>) -> MioResult<()> {
+        if self.is_closed() {
+            return Err(MioError::Error("Connection closed".to_owned()))
+        }
+
+        self.tx.send(ConnEvent::Write(self.token, data))
+            .map_err(|_| MioError::Error("Connection closed".to_owned()))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+/// 同一个服务器的所有连接共用的状态
+pub struct Shared {
+    pub tx: Sender<ConnEvent>,
+    pub thread_pool: Rc<Pool>,
+    pub buffers: BufferPool,
+    pub stats: ServerStats,
+    pub handle: Arc<Handle>,
+    pub classify: Arc<Classify>,
+    pub reject: Arc<Reject>,
 }
 
 pub struct Connection {
     pub tcp_stream: TcpStream,
     pub token: Token,
-    pub stream_data: Arc<Mutex<StreamData>>,
     pub closing: bool,
-    tx: Sender<ConnEvent>,
-    thread_pool: Rc<Pool>,
-    handle: Arc<Handle>,
+    pub reason: CloseReason,
+    pub error: Option<io::Error>,
+    pub info: ConnInfo,
+    pub last_active: Instant,
+    //交给线程池处理时为 None，处理完通过 ConnEvent::Done 还回来
+    stream_data: Option<StreamData>,
+    //处理期间其他线程写入的数据，还回来后追加在响应后面
+    pending: Buffer,
+    closed: Arc<AtomicBool>,
+    shared: Rc<Shared>,
 }
 
 impl Connection {
-    pub fn new(token: Token, tcp_stream: TcpStream, tx: Sender<ConnEvent>, thread_pool: Rc<Pool>, handle: Arc<Handle>,) -> Connection {
+    pub fn new(token: Token, tcp_stream: TcpStream, shared: Rc<Shared>) -> Connection {
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let peer_addr = tcp_stream.peer_addr().unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+
+        let mut stream_data = StreamData::new(shared.buffers.get(READ_SIZE), shared.buffers.get(0));
+        stream_data.remote_addr = peer_addr;
+
+        stream_data.conn = Some(ConnWriter {
+            token: token,
+            tx: shared.tx.clone(),
+            closed: closed.clone(),
+        });
+
         Connection {
             tcp_stream: tcp_stream,
             token: token,
-            stream_data: Arc::new(Mutex::new(StreamData::new(Vec::with_capacity(1024), Vec::with_capacity(1024)))),
             closing: false,
-            tx: tx,
-            thread_pool: thread_pool,
-            handle: handle,
+            reason: CloseReason::Closed,
+            error: None,
+            info: ConnInfo {
+                id: token.0,
+                peer_addr: peer_addr,
+                accepted_at: Instant::now(),
+                requests: 0,
+                bytes_read: 0,
+                bytes_written: 0,
+            },
+            last_active: Instant::now(),
+            stream_data: Some(stream_data),
+            pending: Buffer::new(),
+            closed: closed,
+            shared: shared,
         }
     }
 
-    pub fn reader(&mut self) {
-        let mut stream_data = self.stream_data.lock().unwrap();
+    /// 读取数据并处理，返回需要立即重新注册的事件；交给线程池时返回 None，处理完后通过通道还回 StreamData
+    pub fn reader(&mut self) -> Option<Ready> {
+        let mut stream_data = match self.stream_data.take() {
+            Some(stream_data) => stream_data,
+            None => return None,
+        };
 
-        loop {
+        //边沿触发，直接读进读缓冲区的空闲空间，读到 WouldBlock 为止
+        self.last_active = Instant::now();
 
-            let mut buf = [0; 1024];
-
-            match self.tcp_stream.read(&mut buf) {
+        loop {
+            match stream_data.reader.read_from(&mut self.tcp_stream, READ_SIZE) {
+                Ok(0) => {
+                    self.close(CloseReason::Hup, None);
+                    self.stream_data = Some(stream_data);
+                    return None;
+                }
                 Ok(size) => {
-                    if size == 0 {
-                        self.closing = true;
-                        return;
-                    } else {
-                        stream_data.reader.extend_from_slice(&buf[0..size]);
-                        if size < 1024 {
-                            break;
-                        }
-                    }
+                    self.info.bytes_read += size;
+                    self.shared.stats.bytes_in(size);
                 }
+                Err(ref err) if err.kind() == WouldBlock => break,
                 Err(err) => {
-                    //todo 会有这个错吗
-                    if let WouldBlock = err.kind() {
-                        break;
-                    } else {
-                        self.closing = true;
-                        return;
-                    }
+                    self.close(CloseReason::Error, Some(err));
+                    self.stream_data = Some(stream_data);
+                    return None;
                 }
             }
-
         }
 
-        stream_data.remote_addr = self.tcp_stream.peer_addr().unwrap();
+        //内联处理直接在事件循环线程执行，并立即尝试写回
+        let priority = match (self.shared.classify)(&stream_data) {
+            Dispatch::Inline => {
+                run(&self.shared, &mut stream_data);
+                return Some(self.done(stream_data));
+            }
+            Dispatch::Pool => Priority::Normal,
+            Dispatch::Priority(priority) => priority,
+        };
 
-        let tx = self.tx.clone();
-        let token = self.token.clone();
+        //线程池过载时直接拒绝，不再排队；高优先级的请求不拒绝
+        if self.shared.thread_pool.is_overloaded_for(priority) {
+            self.shared.thread_pool.reject();
+            (self.shared.reject)(&mut stream_data);
+            return Some(self.done(stream_data));
+        }
 
-        let handle = self.handle.clone();
+        let tx = self.shared.tx.clone();
+        let token = self.token.clone();
 
-        let stream_data = self.stream_data.clone();
+        let handle = self.shared.handle.clone();
+        let stats = self.shared.stats.clone();
 
-        self.thread_pool.execute(move || {
+        self.shared.thread_pool.execute_with(priority, move || {
 
-            handle(stream_data);
+            if panic::catch_unwind(AssertUnwindSafe(|| handle(&mut stream_data))).is_err() {
+                stats.panicked();
+                stream_data.close();
+            }
 
-            tx.send(ConnEvent::Write(token)).is_ok();
+            let _ = tx.send(ConnEvent::Done(token, stream_data));
 
         });
+
+        None
+    }
+
+    /// 处理完成，收回 StreamData 并尝试写出响应
+    pub fn done(&mut self, mut stream_data: StreamData) -> Ready {
+        if !self.pending.is_empty() {
+            stream_data.writer.extend_from_slice(&self.pending);
+            self.pending.clear();
+        }
+
+        //大请求撑大的读缓冲区还回池里
+        self.shared.buffers.trim(&mut stream_data.reader, TRIM_SIZE);
+
+        self.shared.stats.requests(stream_data.requests - self.info.requests);
+        self.info.requests = stream_data.requests;
+
+        self.stream_data = Some(stream_data);
+        self.writer()
+    }
+
+    /// 追加其他线程写入的数据，处理期间先暂存，返回 None 表示不需要重新注册
+    pub fn write(&mut self, data: Vec<u8>) -> Option<Ready> {
+        self.last_active = Instant::now();
+
+        match self.stream_data {
+            Some(ref mut stream_data) => stream_data.writer.extend_from_slice(&data),
+            None => {
+                self.pending.extend_from_slice(&data);
+                return None
+            }
+        }
+
+        Some(self.writer())
+    }
+
+    pub fn close(&mut self, reason: CloseReason, error: Option<io::Error>) {
+        self.closing = true;
+        self.reason = reason;
+        self.error = error;
+    }
+
+    /// 没有交给线程池处理，也没有待写数据
+    pub fn is_idle(&self) -> bool {
+        self.stream_data.as_ref().map_or(false, |stream_data| stream_data.writer.is_empty())
     }
 
-    pub fn writer(&mut self) {
-        let ref mut writer = self.stream_data.lock().unwrap().writer;
+    /// 写出待写数据，返回接下来需要等待的事件
+    pub fn writer(&mut self) -> Ready {
+        let stream_data = match self.stream_data {
+            Some(ref mut stream_data) => stream_data,
+            None => return Ready::empty(),
+        };
 
-        match self.tcp_stream.write(writer) {
-            Ok(size) => { ;
-                if size == 0 {
+        let closing = stream_data.closing;
+        let ref mut writer = stream_data.writer;
+
+        if !writer.is_empty() {
+            match self.tcp_stream.write(writer) {
+                Ok(size) => {
+                    if size == 0 {
+                        self.closing = true;
+                        self.reason = CloseReason::Hup;
+                        return Ready::empty();
+                    }
+
+                    writer.consume(size);
+                    self.info.bytes_written += size;
+                    self.shared.stats.bytes_out(size);
+                    self.last_active = Instant::now();
+                },
+                Err(ref err) if err.kind() == WouldBlock => {},
+                Err(err) => {
                     self.closing = true;
-                    return;
+                    self.reason = CloseReason::Error;
+                    self.error = Some(err);
+                    return Ready::empty();
                 }
-
-                writer.clear();
-            },
-            Err(_) => {
-                self.closing = true;
-                return;
             }
         }
 
-        self.tx.send(ConnEvent::Read(self.token)).is_ok();
+        if writer.is_empty() && closing {
+            self.closing = true;
+            self.reason = CloseReason::Closed;
+            return Ready::empty();
+        }
 
+        //没写完的继续等待可写，没有待写数据时继续读
+        if writer.is_empty() {
+            self.shared.buffers.trim(writer, TRIM_SIZE);
+            Ready::readable()
+        } else {
+            Ready::writable()
+        }
 
     }
 }
 
+//处理函数 panic 时 StreamData 还在手上，关闭连接而不是让客户端一直等下去
+fn run(shared: &Shared, stream_data: &mut StreamData) {
+    if panic::catch_unwind(AssertUnwindSafe(|| (shared.handle)(stream_data))).is_err() {
+        shared.stats.panicked();
+        stream_data.close();
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Release);
+
+        //线程池还在处理时 StreamData 不在连接上，处理完发回来时由 Server 还回池里
+        if let Some(stream_data) = self.stream_data.take() {
+            self.shared.buffers.put(stream_data.reader);
+            self.shared.buffers.put(stream_data.writer);
+        }
+    }
+}
+
 impl Evented for Connection {
     fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
                 -> io::Result<()>
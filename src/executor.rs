@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll as TaskPoll, Wake, Waker};
+
+use mio::{Registration, SetReadiness, Ready, Poll, Token, PollOpt, Evented};
+
+use util::sync::lock;
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+struct Task {
+    future: Mutex<Option<BoxFuture>>,
+    spawner: Spawner,
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        let spawner = self.spawner.clone();
+        spawner.schedule(self);
+    }
+}
+
+/// 向 Server 事件循环提交 Future，可在任意线程使用
+#[derive(Clone)]
+pub struct Spawner {
+    queue: Arc<Mutex<VecDeque<Arc<Task>>>>,
+    set_readiness: SetReadiness,
+}
+
+impl Spawner {
+    pub fn spawn<F>(&self, future: F)
+        where F: Future<Output = ()> + Send + 'static
+    {
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(future))),
+            spawner: self.clone(),
+        });
+
+        self.schedule(task);
+    }
+
+    fn schedule(&self, task: Arc<Task>) {
+        lock(&self.queue).push_back(task);
+        let _ = self.set_readiness.set_readiness(Ready::readable());
+    }
+}
+
+/// 在事件循环线程上轮询 Future 的执行器
+///
+/// 被唤醒的任务放进就绪队列并通过 Registration 通知事件循环，Future 里不应有阻塞操作。
+pub struct Executor {
+    registration: Registration,
+    spawner: Spawner,
+}
+
+impl Executor {
+    pub fn new() -> Executor {
+        let (registration, set_readiness) = Registration::new2();
+
+        Executor {
+            registration: registration,
+            spawner: Spawner {
+                queue: Arc::new(Mutex::new(VecDeque::new())),
+                set_readiness: set_readiness,
+            },
+        }
+    }
+
+    pub fn spawner(&self) -> Spawner {
+        self.spawner.clone()
+    }
+
+    /// 轮询就绪的任务，每轮最多处理进入时队列里的任务数，避免一直自唤醒的任务卡住事件循环
+    pub fn run_ready(&self) {
+        let _ = self.spawner.set_readiness.set_readiness(Ready::empty());
+
+        let count = lock(&self.spawner.queue).len();
+
+        for _ in 0..count {
+            let task = match lock(&self.spawner.queue).pop_front() {
+                Some(task) => task,
+                None => break,
+            };
+
+            let waker = Waker::from(task.clone());
+            let mut cx = Context::from_waker(&waker);
+
+            let mut slot = lock(&task.future);
+
+            //Future panic 时直接丢弃，不能让事件循环线程跟着退出
+            if let Some(mut future) = slot.take() {
+                if let Ok(TaskPoll::Pending) = panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(&mut cx))) {
+                    *slot = Some(future);
+                }
+            }
+        }
+
+        if !lock(&self.spawner.queue).is_empty() {
+            let _ = self.spawner.set_readiness.set_readiness(Ready::readable());
+        }
+    }
+}
+
+impl Evented for Executor {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+                -> io::Result<()>
+    {
+        Evented::register(&self.registration, poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt)
+                  -> io::Result<()>
+    {
+        Evented::reregister(&self.registration, poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        Evented::deregister(&self.registration, poll)
+    }
+}
@@ -0,0 +1,204 @@
+use std::fmt::{self, Debug, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use connection::ConnWriter;
+use error::{MioResult, MioError};
+use util::sync::lock;
+use util::timer::{Timer, TimerHandle};
+
+/// Server-Sent Events 事件
+#[derive(Debug, Clone, Default)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    retry: Option<u64>,
+    data: String,
+}
+
+impl Event {
+    pub fn new<S>(data: S) -> Event
+        where S: Into<String>
+    {
+        Event {
+            data: data.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn id<S>(&mut self, id: S) -> &mut Event
+        where S: Into<String>
+    {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn event<S>(&mut self, event: S) -> &mut Event
+        where S: Into<String>
+    {
+        self.event = Some(event.into());
+        self
+    }
+
+    pub fn retry(&mut self, millis: u64) -> &mut Event {
+        self.retry = Some(millis);
+        self
+    }
+
+    pub fn data<S>(&mut self, data: S) -> &mut Event
+        where S: Into<String>
+    {
+        self.data = data.into();
+        self
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = String::new();
+
+        if let Some(ref id) = self.id {
+            buf.push_str(&format!("id: {}\n", single_line(id)));
+        }
+
+        if let Some(ref event) = self.event {
+            buf.push_str(&format!("event: {}\n", single_line(event)));
+        }
+
+        if let Some(retry) = self.retry {
+            buf.push_str(&format!("retry: {}\n", retry));
+        }
+
+        //多行数据每行一个 data 字段
+        for line in self.data.split('\n') {
+            buf.push_str(&format!("data: {}\n", line.trim_end_matches('\r')));
+        }
+
+        buf.push('\n');
+        buf.into_bytes()
+    }
+}
+
+fn single_line(s: &str) -> &str {
+    s.split(|c| c == '\r' || c == '\n').next().unwrap_or("")
+}
+
+struct Inner {
+    pending: Vec<u8>,
+    conn: Option<ConnWriter>,
+    closed: bool,
+}
+
+/// 事件流句柄，可 clone 到其他线程推送事件
+///
+/// 响应头写出之前推送的事件会先缓存，随响应头一起发送。
+#[derive(Clone)]
+pub struct EventStream {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl EventStream {
+    pub fn new() -> EventStream {
+        EventStream {
+            inner: Arc::new(Mutex::new(Inner {
+                pending: Vec::new(),
+                conn: None,
+                closed: false,
+            })),
+        }
+    }
+
+    pub fn send(&self, event: &Event) -> MioResult<()> {
+        self.write(&event.to_bytes())
+    }
+
+    pub fn comment(&self, comment: &str) -> MioResult<()> {
+        let mut buf = String::new();
+
+        for line in comment.split('\n') {
+            buf.push_str(&format!(": {}\n", line.trim_end_matches('\r')));
+        }
+
+        buf.push('\n');
+        self.write(buf.as_bytes())
+    }
+
+    /// 用定时器定时发送注释行保持连接，不占用单独的线程
+    ///
+    /// 连接关闭、事件流关闭或者所有句柄都丢弃后自动停止，也可以用返回的句柄取消。
+    pub fn keep_alive(&self, timer: &Timer, interval: Duration) -> TimerHandle {
+        let inner = Arc::downgrade(&self.inner);
+
+        timer.schedule_repeat(interval, move || {
+            let stream = match inner.upgrade() {
+                Some(inner) => EventStream { inner: inner },
+                None => return false,
+            };
+
+            //还没绑定到连接时不缓存注释，否则会一直堆积在 pending 里
+            if !stream.is_attached() {
+                return !stream.is_closed()
+            }
+
+            stream.comment("keep-alive").is_ok()
+        })
+    }
+
+    pub fn is_attached(&self) -> bool {
+        lock(&self.inner).conn.is_some()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        let inner = lock(&self.inner);
+        inner.closed || inner.conn.as_ref().map_or(false, |conn| conn.is_closed())
+    }
+
+    /// 不再发送，之后推送事件都返回错误
+    pub fn close(&self) {
+        let mut inner = lock(&self.inner);
+
+        inner.closed = true;
+        inner.pending.clear();
+    }
+
+    /// 绑定到连接，绑定之前缓存的数据交给 f 写出
+    ///
+    /// f 在锁内执行，之后发送的事件一定排在这些数据后面。
+    pub fn attach<F>(&self, conn: ConnWriter, f: F)
+        where F: FnOnce(Vec<u8>)
+    {
+        let mut inner = lock(&self.inner);
+
+        inner.conn = Some(conn);
+        f(::std::mem::replace(&mut inner.pending, Vec::new()));
+    }
+
+    fn write(&self, data: &[u8]) -> MioResult<()> {
+        //发送时不持有 inner 的锁
+        let conn = {
+            let mut inner = lock(&self.inner);
+
+            if inner.closed {
+                return Err(MioError::Error("Event stream closed".to_owned()))
+            }
+
+            match inner.conn {
+                Some(ref conn) => conn.clone(),
+                None => {
+                    inner.pending.extend_from_slice(data);
+                    return Ok(())
+                }
+            }
+        };
+
+        if conn.is_closed() {
+            return Err(MioError::Error("Event stream closed".to_owned()))
+        }
+
+        conn.write(data)
+    }
+}
+
+impl Debug for EventStream {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "EventStream {{ attached: {} }}", self.is_attached())
+    }
+}
@@ -1,11 +1,10 @@
-use std::sync::{Arc, Mutex};
-
 use std::io::Write;
 use std::str::FromStr;
 
 use httparse;
 
 use stream_data::StreamData;
+use buffer::Buffer;
 use error::MioResult;
 use error::MioError;
 
@@ -13,32 +12,34 @@ pub use self::request::Request;
 pub use self::response::Response;
 pub use self::http_code::StatusCode;
 pub use self::http_method::Method;
-use self::http_date::HTTPDate;
+pub use self::event_stream::{Event, EventStream};
+pub use self::http_date::HTTPDate;
 
 mod http_code;
 mod http_date;
+mod event_stream;
 mod http_method;
 mod request;
 mod response;
 
-pub struct Http {
-    stream_data: Arc<Mutex<StreamData>>,
+pub struct Http<'a> {
+    stream_data: &'a mut StreamData,
 }
 
-impl Http {
-    pub fn new(stream_data: Arc<Mutex<StreamData>>) -> Http {
+impl<'a> Http<'a> {
+    pub fn new(stream_data: &'a mut StreamData) -> Http<'a> {
         Http {
             stream_data: stream_data,
         }
     }
 
     pub fn decode(&mut self) -> MioResult<Option<Request>> {
-        let mut stream_data = self.stream_data.lock().unwrap();
+        let stream_data = &mut *self.stream_data;
 
         let (method, path, headers, amt) = {
             let mut headers = [httparse::EMPTY_HEADER; 24];
             let mut req = httparse::Request::new(&mut headers);
-            let res = req.parse(&mut stream_data.reader)?;
+            let res = req.parse(&stream_data.reader)?;
 
             let amt = match res {
                 httparse::Status::Complete(amt) => amt,
@@ -69,29 +70,56 @@ impl Http {
             }
         }
 
-        request.data = stream_data.reader.split_off(amt);
-        stream_data.reader.clear();
+        stream_data.reader.consume(amt);
+        //只拷贝请求体，读缓冲区留给连接继续使用
+        let len = stream_data.reader.len();
+        request.data = stream_data.reader.split_to(len);
 
         Ok(Some(request))
     }
 
-    pub fn encode(&mut self, response: Response) {
-        let mut stream_data = self.stream_data.lock().unwrap();
+    /// head 为 true 时是 HEAD 请求的响应，只写出头部
+    pub fn encode(&mut self, response: Response, head: bool) {
+        //事件流绑定到连接，之后的事件直接写入连接
+        if let Some(stream) = write_response(&mut self.stream_data.writer, response, head) {
+            if let Some(conn) = self.stream_data.conn() {
+                let writer = &mut self.stream_data.writer;
+                stream.attach(conn, |pending| writer.extend_from_slice(&pending));
+            }
+        }
+    }
+}
 
-        write!(stream_data, "HTTP/1.1 {} {}\r\n", response.status_code.0, response.status_code.default_reason_phrase()).unwrap();
-        write!(stream_data, "Data: {}\r\n", HTTPDate::new().to_string()).unwrap();
-        write!(stream_data, "Server: Webserver\r\n").unwrap();
+/// 写出状态行、头部和数据，事件流由调用方绑定到连接
+///
+/// head 为 true 时不管处理函数怎样设置响应，都不写出响应体。
+pub fn write_response(writer: &mut Buffer, mut response: Response, head: bool) -> Option<EventStream> {
+    if head {
+        response.set_head(true);
+    }
 
-        if let Some(data_length) = response.data_length {
-            write!(stream_data, "Content-Length: {}\r\n", data_length).unwrap();
-        }
+    write!(writer, "HTTP/1.1 {} {}\r\n", response.status_code.0, response.status_code.default_reason_phrase()).unwrap();
+    write!(writer, "Data: {}\r\n", HTTPDate::new().to_string()).unwrap();
+    write!(writer, "Server: Webserver\r\n").unwrap();
 
-        for (key, value) in response.headers {
-            write!(stream_data, "{}: {}\r\n", key, value).unwrap();
+    if let Some(data_length) = response.data_length {
+        if has_content_length(response.status_code.0) {
+            write!(writer, "Content-Length: {}\r\n", data_length).unwrap();
         }
+    }
 
-        write!(stream_data, "\r\n").unwrap();
-
-        stream_data.write(&response.data).unwrap();
+    for (key, value) in response.headers {
+        write!(writer, "{}: {}\r\n", key, value).unwrap();
     }
+
+    write!(writer, "\r\n").unwrap();
+
+    writer.extend_from_slice(&response.data);
+
+    response.event_stream
+}
+
+/// 1xx 和 204 的响应不能带 Content-Length（RFC 7230 3.3.2）
+pub fn has_content_length(status: u16) -> bool {
+    status >= 200 && status != 204
 }
@@ -50,7 +50,17 @@ impl Request {
     pub fn get_header<'a, S>(&self, key: S) -> Option<String>
         where S: Into<&'a str>
     {
-        self.headers.get(key.into()).map(|v| v.to_string())
+        let key = key.into();
+
+        //头部名不区分大小写，HTTP/2 的头部名都是小写
+        self.headers.get(key)
+            .or_else(|| self.headers.iter().find(|&(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v))
+            .map(|v| v.to_string())
+    }
+
+    /// SSE 断线重连时客户端带上的最后一个事件 id
+    pub fn last_event_id(&self) -> Option<String> {
+        self.get_header("Last-Event-ID")
     }
 
     pub fn remote_addr(&self) -> &SocketAddr {
@@ -6,6 +6,7 @@ use serde::Serialize;
 use serde_json;
 
 use super::http_code::StatusCode;
+use super::event_stream::EventStream;
 use error::MioResult;
 
 #[derive(Debug)]
@@ -14,6 +15,9 @@ pub struct Response {
     pub headers: HashMap<String, String>,
     pub data_length: Option<usize>,
     pub data: Vec<u8>,
+    pub event_stream: Option<EventStream>,
+    //HEAD 请求的响应，只写状态和头部
+    head: bool,
 }
 
 impl Response {
@@ -23,6 +27,8 @@ impl Response {
             headers: headers,
             data_length: data_length,
             data: data,
+            event_stream: None,
+            head: false,
         }
     }
 
@@ -40,13 +46,8 @@ impl Response {
     pub fn from_data<C, D>(&mut self, content_type: C,data: D) -> MioResult<&mut Response>
         where C: Into<String>, D: Into<Vec<u8>>
     {
-        let data = data.into();
-        let data_len = data.len();
-
         self.headers.insert("Content-Type".to_owned(), content_type.into());
-
-        self.data_length = Some(data_len);
-        self.data = data;
+        self.set_body(data.into());
         Ok(self)
     }
 
@@ -55,8 +56,11 @@ impl Response {
     {
         let file_size = file.metadata().ok().map(|v| v.len() as usize);
 
+        //HEAD 只需要文件大小，不读内容
         let mut data: Vec<u8> = Vec::new();
-        file.read_to_end(&mut data)?;
+        if !self.head {
+            file.read_to_end(&mut data)?;
+        }
 
         self.headers.insert("Content-Type".to_owned(), content_type.into());
 
@@ -68,41 +72,75 @@ impl Response {
     pub fn from_text<S>(&mut self, string: S) -> MioResult<&mut Response>
         where S: Into<String>
     {
-        let string = string.into();
-        let data_len = string.len();
-
         self.headers.insert("Content-Type".to_owned(), "text/plain; charset=UTF-8".to_owned());
-
-        self.data_length = Some(data_len);
-        self.data = string.into();
+        self.set_body(string.into().into_bytes());
         Ok(self)
     }
 
     pub fn from_html<S>(&mut self, string: S) -> MioResult<&mut Response>
         where S: Into<String>
     {
-        let string = string.into();
-        let data_len = string.len();
-
         self.headers.insert("Content-Type".to_owned(), "text/html; charset=UTF-8".to_owned());
-
-        self.data_length = Some(data_len);
-        self.data = string.into();
+        self.set_body(string.into().into_bytes());
         Ok(self)
     }
 
     pub fn from_json<S: Serialize>(&mut self, value: S) -> MioResult<&mut Response> {
         let data = serde_json::to_vec(&value)?;
-        let data_len = data.len();
 
         self.headers.insert("Content-Type".to_owned(), "application/json; charset=UTF-8".to_owned());
-
-        self.data_length = Some(data_len);
-        self.data = data;
+        self.set_body(data);
 
         Ok(self)
     }
 
+    /// 以 text/event-stream 响应，返回的句柄用于推送事件
+    ///
+    /// 只支持 HTTP/1.1，HTTP/2 的请求返回 501，推送事件返回错误。
+    pub fn event_stream(&mut self) -> EventStream {
+        let stream = EventStream::new();
+
+        self.headers.insert("Content-Type".to_owned(), "text/event-stream".to_owned());
+        self.headers.insert("Cache-Control".to_owned(), "no-cache".to_owned());
+        self.headers.insert("Connection".to_owned(), "keep-alive".to_owned());
+
+        self.data_length = None;
+        self.data.clear();
+
+        //HEAD 请求没有响应体，推送的事件直接返回错误
+        if self.head {
+            stream.close();
+        } else {
+            self.event_stream = Some(stream.clone());
+        }
+
+        stream
+    }
+
+    /// 作为 HEAD 请求的响应，Content-Length 保持不变，但不写出响应体
+    pub fn set_head(&mut self, head: bool) -> &mut Response {
+        self.head = head;
+
+        if head {
+            self.data = Vec::new();
+
+            if let Some(stream) = self.event_stream.take() {
+                stream.close();
+            }
+        }
+
+        self
+    }
+
+    pub fn is_head(&self) -> bool {
+        self.head
+    }
+
+    fn set_body(&mut self, data: Vec<u8>) {
+        self.data_length = Some(data.len());
+        self.data = if self.head { Vec::new() } else { data };
+    }
+
     pub fn status(&mut self, code: u16) -> &mut Response {
         self.status_code = code.into();
         self
@@ -0,0 +1,176 @@
+pub const DATA: u8 = 0x0;
+pub const HEADERS: u8 = 0x1;
+pub const PRIORITY: u8 = 0x2;
+pub const RST_STREAM: u8 = 0x3;
+pub const SETTINGS: u8 = 0x4;
+pub const PUSH_PROMISE: u8 = 0x5;
+pub const PING: u8 = 0x6;
+pub const GOAWAY: u8 = 0x7;
+pub const WINDOW_UPDATE: u8 = 0x8;
+pub const CONTINUATION: u8 = 0x9;
+
+pub const FLAG_END_STREAM: u8 = 0x1;
+pub const FLAG_ACK: u8 = 0x1;
+pub const FLAG_END_HEADERS: u8 = 0x4;
+pub const FLAG_PADDED: u8 = 0x8;
+pub const FLAG_PRIORITY: u8 = 0x20;
+
+pub const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+pub const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+pub const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+pub const HEADER_LEN: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    NoError = 0x0,
+    ProtocolError = 0x1,
+    InternalError = 0x2,
+    FlowControlError = 0x3,
+    SettingsTimeout = 0x4,
+    StreamClosed = 0x5,
+    FrameSizeError = 0x6,
+    RefusedStream = 0x7,
+    Cancel = 0x8,
+    CompressionError = 0x9,
+    ConnectError = 0xa,
+    EnhanceYourCalm = 0xb,
+    InadequateSecurity = 0xc,
+    Http11Required = 0xd,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameHeader {
+    pub length: usize,
+    pub kind: u8,
+    pub flags: u8,
+    pub stream_id: u32,
+}
+
+impl FrameHeader {
+    pub fn parse(buf: &[u8]) -> FrameHeader {
+        FrameHeader {
+            length: (buf[0] as usize) << 16 | (buf[1] as usize) << 8 | buf[2] as usize,
+            kind: buf[3],
+            flags: buf[4],
+            stream_id: read_u32(&buf[5..9]) & 0x7fff_ffff,
+        }
+    }
+
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag == flag
+    }
+}
+
+pub fn read_u32(buf: &[u8]) -> u32 {
+    (buf[0] as u32) << 24 | (buf[1] as u32) << 16 | (buf[2] as u32) << 8 | buf[3] as u32
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]);
+}
+
+pub fn write_frame(out: &mut Vec<u8>, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    let len = payload.len();
+
+    out.extend_from_slice(&[(len >> 16) as u8, (len >> 8) as u8, len as u8, kind, flags]);
+    push_u32(out, stream_id & 0x7fff_ffff);
+    out.extend_from_slice(payload);
+}
+
+pub fn write_settings(out: &mut Vec<u8>, settings: &[(u16, u32)]) {
+    let mut payload = Vec::with_capacity(settings.len() * 6);
+
+    for &(id, value) in settings {
+        payload.extend_from_slice(&[(id >> 8) as u8, id as u8]);
+        push_u32(&mut payload, value);
+    }
+
+    write_frame(out, SETTINGS, 0, 0, &payload);
+}
+
+pub fn write_window_update(out: &mut Vec<u8>, stream_id: u32, increment: u32) {
+    let mut payload = Vec::with_capacity(4);
+    push_u32(&mut payload, increment & 0x7fff_ffff);
+
+    write_frame(out, WINDOW_UPDATE, 0, stream_id, &payload);
+}
+
+pub fn write_rst_stream(out: &mut Vec<u8>, stream_id: u32, code: ErrorCode) {
+    let mut payload = Vec::with_capacity(4);
+    push_u32(&mut payload, code as u32);
+
+    write_frame(out, RST_STREAM, 0, stream_id, &payload);
+}
+
+pub fn write_goaway(out: &mut Vec<u8>, last_stream_id: u32, code: ErrorCode) {
+    let mut payload = Vec::with_capacity(8);
+    push_u32(&mut payload, last_stream_id & 0x7fff_ffff);
+    push_u32(&mut payload, code as u32);
+
+    write_frame(out, GOAWAY, 0, 0, &payload);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_header() {
+        let header = FrameHeader::parse(&[0x00, 0x40, 0x01, HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 0x80, 0x00, 0x00, 0x03]);
+
+        assert_eq!(header.length, 16385);
+        assert_eq!(header.kind, HEADERS);
+        assert!(header.has(FLAG_END_HEADERS));
+        assert!(header.has(FLAG_END_STREAM));
+        assert!(!header.has(FLAG_PADDED));
+        //保留位要忽略
+        assert_eq!(header.stream_id, 3);
+    }
+
+    #[test]
+    fn write_and_parse() {
+        let mut out = Vec::new();
+        write_frame(&mut out, DATA, FLAG_END_STREAM, 5, b"hello");
+
+        let header = FrameHeader::parse(&out);
+        assert_eq!(out.len(), HEADER_LEN + 5);
+        assert_eq!(header.length, 5);
+        assert_eq!(header.kind, DATA);
+        assert_eq!(header.flags, FLAG_END_STREAM);
+        assert_eq!(header.stream_id, 5);
+        assert_eq!(&out[HEADER_LEN..], b"hello");
+    }
+
+    #[test]
+    fn settings_payload() {
+        let mut out = Vec::new();
+        write_settings(&mut out, &[(SETTINGS_MAX_CONCURRENT_STREAMS, 100), (SETTINGS_INITIAL_WINDOW_SIZE, 65535)]);
+
+        assert_eq!(out, vec![
+            0x00, 0x00, 0x0c, SETTINGS, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x03, 0x00, 0x00, 0x00, 0x64,
+            0x00, 0x04, 0x00, 0x00, 0xff, 0xff,
+        ]);
+    }
+
+    #[test]
+    fn control_frames() {
+        let mut out = Vec::new();
+        write_window_update(&mut out, 1, 0x8000_0010);
+        assert_eq!(out, vec![0x00, 0x00, 0x04, WINDOW_UPDATE, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x10]);
+
+        let mut out = Vec::new();
+        write_rst_stream(&mut out, 7, ErrorCode::Cancel);
+        assert_eq!(out, vec![0x00, 0x00, 0x04, RST_STREAM, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x08]);
+
+        let mut out = Vec::new();
+        write_goaway(&mut out, 9, ErrorCode::ProtocolError);
+        assert_eq!(FrameHeader::parse(&out).length, 8);
+        assert_eq!(read_u32(&out[HEADER_LEN..]), 9);
+        assert_eq!(read_u32(&out[HEADER_LEN + 4..]), ErrorCode::ProtocolError as u32);
+    }
+}
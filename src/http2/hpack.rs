@@ -0,0 +1,483 @@
+use std::collections::VecDeque;
+
+use error::{MioResult, MioError};
+use super::huffman::{self, Huffman};
+
+const STATIC_TABLE: [(&'static str, &'static str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+fn error(msg: &str) -> MioError {
+    MioError::Error(format!("Hpack error: {}", msg))
+}
+
+pub fn decode_int(data: &[u8], pos: &mut usize, prefix: u8) -> MioResult<usize> {
+    let mask = (1u16 << prefix) as usize - 1;
+
+    let first = *data.get(*pos).ok_or_else(|| error("integer truncated"))? as usize & mask;
+    *pos += 1;
+
+    if first < mask {
+        return Ok(first)
+    }
+
+    let mut value = mask;
+    let mut shift = 0;
+
+    loop {
+        let byte = *data.get(*pos).ok_or_else(|| error("integer truncated"))?;
+        *pos += 1;
+
+        if shift > 28 {
+            return Err(error("integer overflow"))
+        }
+
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Ok(value)
+        }
+    }
+}
+
+pub fn encode_int(value: usize, prefix: u8, flags: u8, out: &mut Vec<u8>) {
+    let mask = (1u16 << prefix) as usize - 1;
+
+    if value < mask {
+        out.push(flags | value as u8);
+        return;
+    }
+
+    out.push(flags | mask as u8);
+
+    let mut value = value - mask;
+
+    while value >= 128 {
+        out.push((value % 128 + 128) as u8);
+        value /= 128;
+    }
+
+    out.push(value as u8);
+}
+
+struct Table {
+    entries: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Table {
+    fn new(max_size: usize) -> Table {
+        Table {
+            entries: VecDeque::new(),
+            size: 0,
+            max_size: max_size,
+        }
+    }
+
+    fn get(&self, index: usize) -> MioResult<(String, String)> {
+        if index == 0 {
+            return Err(error("index 0"))
+        }
+
+        if index <= STATIC_TABLE.len() {
+            let (name, value) = STATIC_TABLE[index - 1];
+            return Ok((name.to_owned(), value.to_owned()))
+        }
+
+        self.entries.get(index - STATIC_TABLE.len() - 1)
+            .cloned()
+            .ok_or_else(|| error("index out of range"))
+    }
+
+    fn insert(&mut self, name: String, value: String) {
+        let size = name.len() + value.len() + 32;
+
+        self.size += size;
+        self.entries.push_front((name, value));
+        self.evict();
+    }
+
+    fn resize(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            match self.entries.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + 32,
+                None => break,
+            }
+        }
+    }
+}
+
+/// HPACK 头部块解码器，每个连接一个，动态表跨头部块保留
+pub struct Decoder {
+    table: Table,
+    max_size: usize,
+    huffman: Huffman,
+}
+
+impl Decoder {
+    pub fn new(max_size: usize) -> Decoder {
+        Decoder {
+            table: Table::new(max_size),
+            max_size: max_size,
+            huffman: Huffman::new(),
+        }
+    }
+
+    pub fn decode(&mut self, data: &[u8]) -> MioResult<Vec<(String, String)>> {
+        let mut headers = Vec::new();
+        let mut pos = 0;
+
+        while pos < data.len() {
+            let byte = data[pos];
+
+            if byte & 0x80 != 0 {
+                //索引头部
+                let index = decode_int(data, &mut pos, 7)?;
+                headers.push(self.table.get(index)?);
+            } else if byte & 0xc0 == 0x40 {
+                //带增量索引的字面量
+                let (name, value) = self.literal(data, &mut pos, 6)?;
+                self.table.insert(name.clone(), value.clone());
+                headers.push((name, value));
+            } else if byte & 0xe0 == 0x20 {
+                //动态表大小更新
+                let size = decode_int(data, &mut pos, 5)?;
+                if size > self.max_size {
+                    return Err(error("table size update too large"))
+                }
+                self.table.resize(size);
+            } else {
+                //不索引 / 永不索引的字面量
+                headers.push(self.literal(data, &mut pos, 4)?);
+            }
+        }
+
+        Ok(headers)
+    }
+
+    fn literal(&self, data: &[u8], pos: &mut usize, prefix: u8) -> MioResult<(String, String)> {
+        let index = decode_int(data, pos, prefix)?;
+
+        let name = if index == 0 {
+            self.string(data, pos)?
+        } else {
+            self.table.get(index)?.0
+        };
+
+        let value = self.string(data, pos)?;
+
+        Ok((name, value))
+    }
+
+    fn string(&self, data: &[u8], pos: &mut usize) -> MioResult<String> {
+        let huffman = *data.get(*pos).ok_or_else(|| error("string truncated"))? & 0x80 != 0;
+        let len = decode_int(data, pos, 7)?;
+
+        if data.len() - *pos < len {
+            return Err(error("string truncated"))
+        }
+
+        let raw = &data[*pos..*pos + len];
+        *pos += len;
+
+        let bytes = if huffman {
+            self.huffman.decode(raw)?
+        } else {
+            raw.to_vec()
+        };
+
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+/// HPACK 编码器，只输出不索引的字面量，不使用动态表
+pub struct Encoder;
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder
+    }
+
+    pub fn encode(&self, headers: &[(String, String)], out: &mut Vec<u8>) {
+        for &(ref name, ref value) in headers {
+            if let Some(index) = STATIC_TABLE.iter().position(|&(n, v)| n == name && v == value) {
+                encode_int(index + 1, 7, 0x80, out);
+                continue;
+            }
+
+            match STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+                Some(index) => encode_int(index + 1, 4, 0x00, out),
+                None => {
+                    out.push(0x00);
+                    self.string(name, out);
+                }
+            }
+
+            self.string(value, out);
+        }
+    }
+
+    fn string(&self, s: &str, out: &mut Vec<u8>) {
+        let len = huffman::encoded_len(s.as_bytes());
+
+        if len < s.len() {
+            encode_int(len, 7, 0x80, out);
+            huffman::encode(s.as_bytes(), out);
+        } else {
+            encode_int(s.len(), 7, 0x00, out);
+            out.extend_from_slice(s.as_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn headers(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter().map(|&(name, value)| (name.to_owned(), value.to_owned())).collect()
+    }
+
+    #[test]
+    fn integers() {
+        //RFC 7541 附录 C.1
+        let cases: [(usize, u8, &str); 3] = [(10, 5, "0a"), (1337, 5, "1f9a0a"), (42, 8, "2a")];
+
+        for &(value, prefix, code) in cases.iter() {
+            let mut out = Vec::new();
+            encode_int(value, prefix, 0, &mut out);
+            assert_eq!(out, hex(code));
+
+            let mut pos = 0;
+            assert_eq!(decode_int(&out, &mut pos, prefix).unwrap(), value);
+            assert_eq!(pos, out.len());
+        }
+
+        let mut pos = 0;
+        assert!(decode_int(&hex("1f9a"), &mut pos, 5).is_err());
+
+        let mut pos = 0;
+        assert!(decode_int(&hex("1f ff ff ff ff ff 01"), &mut pos, 5).is_err());
+    }
+
+    #[test]
+    fn literal_representations() {
+        //RFC 7541 附录 C.2
+        let mut decoder = Decoder::new(4096);
+        let block = hex("400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572");
+        assert_eq!(decoder.decode(&block).unwrap(), headers(&[("custom-key", "custom-header")]));
+        assert_eq!(decoder.table.size, 55);
+
+        let mut decoder = Decoder::new(4096);
+        let block = hex("040c 2f73 616d 706c 652f 7061 7468");
+        assert_eq!(decoder.decode(&block).unwrap(), headers(&[(":path", "/sample/path")]));
+        assert_eq!(decoder.table.size, 0);
+
+        let mut decoder = Decoder::new(4096);
+        let block = hex("1008 7061 7373 776f 7264 0673 6563 7265 74");
+        assert_eq!(decoder.decode(&block).unwrap(), headers(&[("password", "secret")]));
+        assert_eq!(decoder.table.size, 0);
+
+        let mut decoder = Decoder::new(4096);
+        assert_eq!(decoder.decode(&hex("82")).unwrap(), headers(&[(":method", "GET")]));
+    }
+
+    fn requests(blocks: [&str; 3]) {
+        let mut decoder = Decoder::new(4096);
+
+        assert_eq!(decoder.decode(&hex(blocks[0])).unwrap(), headers(&[
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+        ]));
+        assert_eq!(decoder.table.size, 57);
+
+        assert_eq!(decoder.decode(&hex(blocks[1])).unwrap(), headers(&[
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "www.example.com"),
+            ("cache-control", "no-cache"),
+        ]));
+        assert_eq!(decoder.table.size, 110);
+
+        assert_eq!(decoder.decode(&hex(blocks[2])).unwrap(), headers(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", "/index.html"),
+            (":authority", "www.example.com"),
+            ("custom-key", "custom-value"),
+        ]));
+        assert_eq!(decoder.table.size, 164);
+        assert_eq!(decoder.table.get(62).unwrap(), ("custom-key".to_owned(), "custom-value".to_owned()));
+        assert_eq!(decoder.table.get(64).unwrap(), (":authority".to_owned(), "www.example.com".to_owned()));
+    }
+
+    #[test]
+    fn requests_without_huffman() {
+        //RFC 7541 附录 C.3
+        requests([
+            "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+            "8286 84be 5808 6e6f 2d63 6163 6865",
+            "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+        ]);
+    }
+
+    #[test]
+    fn requests_with_huffman() {
+        //RFC 7541 附录 C.4
+        requests([
+            "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+            "8286 84be 5886 a8eb 1064 9cbf",
+            "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+        ]);
+    }
+
+    #[test]
+    fn responses_with_eviction() {
+        //RFC 7541 附录 C.5，动态表只有 256 字节，第二、三个响应会淘汰旧的条目
+        let mut decoder = Decoder::new(256);
+
+        let first = hex("4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77 7777 2e65 7861 6d70 6c65 2e63 6f6d");
+        assert_eq!(decoder.decode(&first).unwrap(), headers(&[
+            (":status", "302"),
+            ("cache-control", "private"),
+            ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+            ("location", "https://www.example.com"),
+        ]));
+        assert_eq!(decoder.table.size, 222);
+
+        assert_eq!(decoder.decode(&hex("4803 3330 37c1 c0bf")).unwrap(), headers(&[
+            (":status", "307"),
+            ("cache-control", "private"),
+            ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+            ("location", "https://www.example.com"),
+        ]));
+        assert_eq!(decoder.table.size, 222);
+        assert_eq!(decoder.table.entries.len(), 4);
+
+        let third = hex("88c1 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 3220 474d 54c0 5a04 677a 6970 7738 666f 6f3d 4153 444a 4b48 514b 425a 584f 5157 454f 5049 5541 5851 5745 4f49 553b 206d 6178 2d61 6765 3d33 3630 303b 2076 6572 7369 6f6e 3d31");
+        assert_eq!(decoder.decode(&third).unwrap(), headers(&[
+            (":status", "200"),
+            ("cache-control", "private"),
+            ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+            ("location", "https://www.example.com"),
+            ("content-encoding", "gzip"),
+            ("set-cookie", "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1"),
+        ]));
+        assert_eq!(decoder.table.size, 215);
+        assert_eq!(decoder.table.entries.len(), 3);
+    }
+
+    #[test]
+    fn table_size_update() {
+        let mut decoder = Decoder::new(4096);
+        decoder.decode(&hex("400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572")).unwrap();
+
+        //大小更新为 0 清空动态表
+        assert!(decoder.decode(&hex("20")).unwrap().is_empty());
+        assert_eq!(decoder.table.size, 0);
+        assert!(decoder.decode(&hex("be")).is_err());
+
+        //不能超过 SETTINGS 里的大小
+        assert!(decoder.decode(&hex("3fe2 1f")).is_err());
+    }
+
+    #[test]
+    fn encoder_round_trip() {
+        let list = headers(&[
+            (":status", "200"),
+            ("content-type", "text/plain; charset=UTF-8"),
+            ("x-custom", "value"),
+            ("content-length", "11"),
+        ]);
+
+        let mut block = Vec::new();
+        Encoder::new().encode(&list, &mut block);
+
+        //":status: 200" 在静态表里，只占一个字节
+        assert_eq!(block[0], 0x88);
+        assert_eq!(Decoder::new(4096).decode(&block).unwrap(), list);
+    }
+
+    #[test]
+    fn reject_truncated_block() {
+        let mut decoder = Decoder::new(4096);
+
+        assert!(decoder.decode(&hex("400a 6375 7374")).is_err());
+        assert!(decoder.decode(&hex("80")).is_err());
+    }
+}
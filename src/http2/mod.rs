@@ -0,0 +1,1099 @@
+//! 明文 HTTP/2（h2c）支持，需要开启 `h2c` feature
+//!
+//! 支持 prior knowledge（直接发送连接前言）和 `Upgrade: h2c` 两种方式，
+//! 每个流收齐后交给 `App` 分发到线程池，响应按流量控制窗口分帧写回。
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use connection::ConnWriter;
+use http::{self, Request, Response, Method, HTTPDate};
+use stream_data::StreamData;
+use util::sync::lock;
+
+use self::frame::*;
+use self::hpack::{Decoder, Encoder};
+
+mod frame;
+mod hpack;
+mod huffman;
+
+pub use self::frame::ErrorCode;
+
+pub const PREFACE: &'static [u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const DEFAULT_WINDOW: i64 = 65535;
+//每个流的接收窗口，也是一个请求体最多缓存的字节数
+const STREAM_WINDOW: i64 = 1 << 20;
+//连接的接收窗口，所有流缓存的数据加起来不超过它
+const CONN_WINDOW: i64 = 16 << 20;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+const MAX_FRAME_SIZE: usize = 16384;
+const MAX_CONCURRENT_STREAMS: usize = 100;
+const HEADER_TABLE_SIZE: usize = 4096;
+
+enum H2Error {
+    Connection(ErrorCode),
+    Stream(u32, ErrorCode),
+}
+
+type H2Result<T> = Result<T, H2Error>;
+
+struct Stream {
+    headers: Vec<(String, String)>,
+    data: Vec<u8>,
+    remote_closed: bool,
+    recv_window: i64,
+    //收到但还没交给处理函数的字节数，占用着连接窗口
+    received: i64,
+    send_window: i64,
+    body: Option<Vec<u8>>,
+    sent: usize,
+    //HEAD 请求，响应时不发送响应体
+    head: bool,
+}
+
+struct Continuation {
+    stream_id: u32,
+    block: Vec<u8>,
+    end_stream: bool,
+}
+
+/// 一个 HTTP/2 连接，跨多次读取保存在 `StreamData` 里
+///
+/// 状态放在锁里，流的响应可以在其他线程通过 `Responder` 写回。
+pub struct Session {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    decoder: Decoder,
+    encoder: Encoder,
+    streams: BTreeMap<u32, Stream>,
+    continuation: Option<Continuation>,
+    preface: bool,
+    started: bool,
+    last_stream_id: u32,
+    send_window: i64,
+    recv_window: i64,
+    initial_window: i64,
+    max_frame_size: usize,
+    goaway_sent: bool,
+    goaway_received: bool,
+    remote_addr: SocketAddr,
+    conn: Option<ConnWriter>,
+    //待写出的帧
+    out: Vec<u8>,
+    //收齐等待分发的请求
+    ready: Vec<(u32, Request)>,
+}
+
+/// 读缓冲区是否以 HTTP/2 连接前言开头（允许前言尚未收全）
+pub fn is_preface(buf: &[u8]) -> bool {
+    if buf.len() >= PREFACE.len() {
+        &buf[..PREFACE.len()] == PREFACE
+    } else {
+        buf.len() >= 4 && PREFACE.starts_with(buf)
+    }
+}
+
+/// `Upgrade: h2c` 请求返回解码后的 HTTP2-Settings 负载
+pub fn upgrade_settings(request: &Request) -> Option<Vec<u8>> {
+    let has_token = |value: &str, token: &str| {
+        value.split(',').any(|v| v.trim().eq_ignore_ascii_case(token))
+    };
+
+    let upgrade = request.get_header("Upgrade")?;
+    let connection = request.get_header("Connection")?;
+
+    if !has_token(&upgrade, "h2c") || !has_token(&connection, "upgrade") || !has_token(&connection, "http2-settings") {
+        return None
+    }
+
+    let settings = request.get_header("HTTP2-Settings")?;
+    base64url_decode(settings.trim())
+}
+
+fn base64url_decode(input: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len() * 3 / 4);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+
+    for c in input.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' | b'+' => 62,
+            b'_' | b'/' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+
+    Some(out)
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session {
+            state: Arc::new(Mutex::new(State::new())),
+        }
+    }
+
+    /// 处理 `Upgrade: h2c`：回复 101，升级前的请求作为流 1，返回流 1 的响应句柄
+    pub fn upgrade(stream_data: &mut StreamData, request: &Request, settings: &[u8]) -> (Session, Option<Responder>) {
+        let session = Session::new();
+
+        let responder = {
+            let mut state = lock(&session.state);
+            state.conn = stream_data.conn();
+
+            state.out.extend_from_slice(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n");
+            state.start();
+
+            let responder = if settings.len() % 6 != 0 || state.apply_settings(settings).is_err() {
+                state.go_away(ErrorCode::ProtocolError);
+                None
+            } else {
+                state.last_stream_id = 1;
+                let mut stream = state.stream(true);
+                stream.head = request.method == Method::Head;
+                state.streams.insert(1, stream);
+
+                Some(Responder {
+                    id: 1,
+                    state: session.state.clone(),
+                })
+            };
+
+            state.emit(stream_data);
+            responder
+        };
+
+        (session, responder)
+    }
+
+    /// 已经发出 GOAWAY，或者对端发来 GOAWAY 且所有流都已响应，写完后应该关闭连接
+    pub fn is_closed(&self) -> bool {
+        let state = lock(&self.state);
+        state.goaway_sent || (state.goaway_received && state.streams.is_empty())
+    }
+
+    /// 解析读缓冲区里所有完整的帧，不完整的帧留到下次读取
+    ///
+    /// 返回收齐的请求和对应流的响应句柄，由调用方分发，可以在其他线程响应。
+    pub fn process(&self, stream_data: &mut StreamData) -> Vec<(Request, Responder)> {
+        let mut state = lock(&self.state);
+
+        if state.conn.is_none() {
+            state.conn = stream_data.conn();
+        }
+        state.remote_addr = stream_data.remote_addr();
+
+        let consumed = state.read_frames(&stream_data.reader);
+        stream_data.reader.consume(consumed);
+
+        state.emit(stream_data);
+
+        let ready = mem::take(&mut state.ready);
+
+        ready.into_iter().map(|(id, request)| {
+            (request, Responder {
+                id: id,
+                state: self.state.clone(),
+            })
+        }).collect()
+    }
+}
+
+/// 一个流的响应句柄，可以 clone 到其他线程，只有第一次发送生效
+#[derive(Clone)]
+pub struct Responder {
+    id: u32,
+    state: Arc<Mutex<State>>,
+}
+
+impl Responder {
+    /// 流已经响应过、被对端重置或连接已关闭时返回 false
+    pub fn send(&self, response: Response) -> bool {
+        let mut state = lock(&self.state);
+
+        let waiting = state.streams.get(&self.id).is_some_and(|stream| stream.remote_closed && stream.body.is_none());
+
+        if !waiting || state.conn.as_ref().is_some_and(|conn| conn.is_closed()) {
+            return false
+        }
+
+        state.respond(self.id, response);
+        state.flush();
+
+        match state.conn.clone() {
+            Some(conn) => {
+                let out = mem::take(&mut state.out);
+                conn.send(out).is_ok()
+            },
+            //没有连接句柄时留到下次 process 写出
+            None => true,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        let state = lock(&self.state);
+        !state.streams.contains_key(&self.id) || state.conn.as_ref().is_some_and(|conn| conn.is_closed())
+    }
+}
+
+impl State {
+    fn new() -> State {
+        State {
+            decoder: Decoder::new(HEADER_TABLE_SIZE),
+            encoder: Encoder::new(),
+            streams: BTreeMap::new(),
+            continuation: None,
+            preface: false,
+            started: false,
+            last_stream_id: 0,
+            send_window: DEFAULT_WINDOW,
+            recv_window: DEFAULT_WINDOW,
+            initial_window: DEFAULT_WINDOW,
+            max_frame_size: MAX_FRAME_SIZE,
+            goaway_sent: false,
+            goaway_received: false,
+            remote_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            conn: None,
+            out: Vec::new(),
+            ready: Vec::new(),
+        }
+    }
+
+    /// 写出 out 里的帧；所有输出都经过连接句柄，和其他线程发出的响应保持先后顺序
+    fn emit(&mut self, stream_data: &mut StreamData) {
+        if self.out.is_empty() {
+            return;
+        }
+
+        let out = mem::take(&mut self.out);
+
+        match self.conn {
+            Some(ref conn) => {
+                if conn.send(out).is_err() {
+                    stream_data.close();
+                }
+            },
+            None => stream_data.writer.extend_from_slice(&out),
+        }
+    }
+
+    fn read_frames(&mut self, input: &[u8]) -> usize {
+        if !self.started {
+            self.start();
+        }
+
+        if self.goaway_sent {
+            return input.len()
+        }
+
+        let mut pos = 0;
+
+        if !self.preface {
+            if !is_preface(input) {
+                self.go_away(ErrorCode::ProtocolError);
+                return input.len()
+            }
+
+            if input.len() < PREFACE.len() {
+                return 0
+            }
+
+            pos = PREFACE.len();
+            self.preface = true;
+        }
+
+        while input.len() - pos >= HEADER_LEN {
+            let header = FrameHeader::parse(&input[pos..]);
+
+            if header.length > MAX_FRAME_SIZE {
+                self.go_away(ErrorCode::FrameSizeError);
+                break;
+            }
+
+            if input.len() - pos - HEADER_LEN < header.length {
+                break;
+            }
+
+            let payload = &input[pos + HEADER_LEN..pos + HEADER_LEN + header.length];
+            pos += HEADER_LEN + header.length;
+
+            match self.frame(header, payload) {
+                Ok(()) => {},
+                Err(H2Error::Stream(id, code)) => {
+                    write_rst_stream(&mut self.out, id, code);
+                    self.remove(id);
+                },
+                Err(H2Error::Connection(code)) => {
+                    self.go_away(code);
+                    break;
+                }
+            }
+        }
+
+        self.flush();
+
+        if self.goaway_sent {
+            input.len()
+        } else {
+            pos
+        }
+    }
+
+    fn start(&mut self) {
+        write_settings(&mut self.out, &[
+            (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_CONCURRENT_STREAMS as u32),
+            (SETTINGS_INITIAL_WINDOW_SIZE, STREAM_WINDOW as u32),
+            (SETTINGS_MAX_FRAME_SIZE, MAX_FRAME_SIZE as u32),
+        ]);
+
+        //连接窗口不能用 SETTINGS 修改，初始的 65535 之外的部分用 WINDOW_UPDATE 给出
+        write_window_update(&mut self.out, 0, (CONN_WINDOW - DEFAULT_WINDOW) as u32);
+        self.recv_window = CONN_WINDOW;
+
+        self.started = true;
+    }
+
+    fn go_away(&mut self, code: ErrorCode) {
+        write_goaway(&mut self.out, self.last_stream_id, code);
+        self.goaway_sent = true;
+    }
+
+    fn stream(&self, remote_closed: bool) -> Stream {
+        Stream {
+            headers: Vec::new(),
+            data: Vec::new(),
+            remote_closed: remote_closed,
+            recv_window: STREAM_WINDOW,
+            received: 0,
+            send_window: self.initial_window,
+            body: None,
+            sent: 0,
+            head: false,
+        }
+    }
+
+    /// 移除流，还没交给处理函数的数据不会再被读取，归还占用的连接窗口
+    fn remove(&mut self, id: u32) {
+        if let Some(stream) = self.streams.remove(&id) {
+            self.refund(stream.received);
+        }
+    }
+
+    fn refund(&mut self, len: i64) {
+        if len > 0 {
+            write_window_update(&mut self.out, 0, len as u32);
+            self.recv_window += len;
+        }
+    }
+
+    fn frame(&mut self, header: FrameHeader, payload: &[u8]) -> H2Result<()> {
+        let id = header.stream_id;
+
+        //头部块没结束之前只能收到同一个流的 CONTINUATION
+        if let Some(ref continuation) = self.continuation {
+            if header.kind != CONTINUATION || id != continuation.stream_id {
+                return Err(H2Error::Connection(ErrorCode::ProtocolError))
+            }
+        }
+
+        match header.kind {
+            DATA => self.on_data(header, payload),
+            HEADERS => {
+                if id == 0 {
+                    return Err(H2Error::Connection(ErrorCode::ProtocolError))
+                }
+
+                let mut block = unpad(&header, payload)?;
+
+                if header.has(FLAG_PRIORITY) {
+                    if block.len() < 5 {
+                        return Err(H2Error::Connection(ErrorCode::FrameSizeError))
+                    }
+                    block = &block[5..];
+                }
+
+                let end_stream = header.has(FLAG_END_STREAM);
+
+                if header.has(FLAG_END_HEADERS) {
+                    self.on_header_block(id, block, end_stream)
+                } else {
+                    self.continuation = Some(Continuation {
+                        stream_id: id,
+                        block: block.to_vec(),
+                        end_stream: end_stream,
+                    });
+                    Ok(())
+                }
+            },
+            CONTINUATION => {
+                let mut continuation = match self.continuation.take() {
+                    Some(continuation) => continuation,
+                    None => return Err(H2Error::Connection(ErrorCode::ProtocolError)),
+                };
+
+                continuation.block.extend_from_slice(payload);
+
+                if header.has(FLAG_END_HEADERS) {
+                    self.on_header_block(id, &continuation.block, continuation.end_stream)
+                } else {
+                    self.continuation = Some(continuation);
+                    Ok(())
+                }
+            },
+            PRIORITY => {
+                if id == 0 {
+                    return Err(H2Error::Connection(ErrorCode::ProtocolError))
+                }
+
+                if payload.len() != 5 {
+                    return Err(H2Error::Stream(id, ErrorCode::FrameSizeError))
+                }
+
+                Ok(())
+            },
+            RST_STREAM => {
+                if id == 0 || id > self.last_stream_id {
+                    return Err(H2Error::Connection(ErrorCode::ProtocolError))
+                }
+
+                if payload.len() != 4 {
+                    return Err(H2Error::Connection(ErrorCode::FrameSizeError))
+                }
+
+                self.remove(id);
+                Ok(())
+            },
+            SETTINGS => {
+                if id != 0 {
+                    return Err(H2Error::Connection(ErrorCode::ProtocolError))
+                }
+
+                if header.has(FLAG_ACK) {
+                    if !payload.is_empty() {
+                        return Err(H2Error::Connection(ErrorCode::FrameSizeError))
+                    }
+                    return Ok(())
+                }
+
+                if payload.len() % 6 != 0 {
+                    return Err(H2Error::Connection(ErrorCode::FrameSizeError))
+                }
+
+                self.apply_settings(payload)?;
+                write_frame(&mut self.out, SETTINGS, FLAG_ACK, 0, &[]);
+                Ok(())
+            },
+            PUSH_PROMISE => Err(H2Error::Connection(ErrorCode::ProtocolError)),
+            PING => {
+                if id != 0 {
+                    return Err(H2Error::Connection(ErrorCode::ProtocolError))
+                }
+
+                if payload.len() != 8 {
+                    return Err(H2Error::Connection(ErrorCode::FrameSizeError))
+                }
+
+                if !header.has(FLAG_ACK) {
+                    write_frame(&mut self.out, PING, FLAG_ACK, 0, payload);
+                }
+
+                Ok(())
+            },
+            GOAWAY => {
+                if id != 0 {
+                    return Err(H2Error::Connection(ErrorCode::ProtocolError))
+                }
+
+                self.goaway_received = true;
+                Ok(())
+            },
+            WINDOW_UPDATE => self.on_window_update(id, payload),
+            //未知类型的帧直接忽略
+            _ => Ok(()),
+        }
+    }
+
+    fn on_data(&mut self, header: FrameHeader, payload: &[u8]) -> H2Result<()> {
+        let id = header.stream_id;
+
+        if id == 0 {
+            return Err(H2Error::Connection(ErrorCode::ProtocolError))
+        }
+
+        //流量控制按整个负载（含填充）计算
+        let len = payload.len() as i64;
+
+        if len > self.recv_window {
+            return Err(H2Error::Connection(ErrorCode::FlowControlError))
+        }
+
+        self.recv_window -= len;
+
+        let data = unpad(&header, payload)?;
+        let end_stream = header.has(FLAG_END_STREAM);
+        let last_stream_id = self.last_stream_id;
+
+        let accepted = match self.streams.get_mut(&id) {
+            None if id > last_stream_id => return Err(H2Error::Connection(ErrorCode::ProtocolError)),
+            None => Err(H2Error::Stream(id, ErrorCode::StreamClosed)),
+            Some(ref stream) if stream.remote_closed => Err(H2Error::Stream(id, ErrorCode::StreamClosed)),
+            Some(ref stream) if len > stream.recv_window => Err(H2Error::Stream(id, ErrorCode::FlowControlError)),
+            Some(stream) => {
+                //数据交给处理函数之前不归还窗口，一个流最多缓存 STREAM_WINDOW 字节
+                stream.recv_window -= len;
+                stream.received += len;
+                stream.data.extend_from_slice(data);
+
+                if end_stream {
+                    stream.remote_closed = true;
+                }
+
+                Ok(stream.recv_window)
+            }
+        };
+
+        match accepted {
+            Ok(_) if end_stream => self.ready(id),
+            Ok(0) => {
+                //窗口用完还没结束，请求体太大，先响应 413 再让对端停止发送
+                self.respond(id, Response::empty(413));
+                write_rst_stream(&mut self.out, id, ErrorCode::NoError);
+                Ok(())
+            },
+            Ok(_) => Ok(()),
+            Err(err) => {
+                //丢弃的数据也算消耗了连接窗口，立即归还
+                self.refund(len);
+                Err(err)
+            }
+        }
+    }
+
+    fn on_header_block(&mut self, id: u32, block: &[u8], end_stream: bool) -> H2Result<()> {
+        //即使要拒绝这个流也必须解码，保持 HPACK 动态表同步
+        let headers = self.decoder.decode(block)
+            .map_err(|_| H2Error::Connection(ErrorCode::CompressionError))?;
+
+        if let Some(stream) = self.streams.get_mut(&id) {
+            //trailers 必须结束流
+            if stream.remote_closed {
+                return Err(H2Error::Stream(id, ErrorCode::StreamClosed))
+            }
+
+            if !end_stream {
+                return Err(H2Error::Connection(ErrorCode::ProtocolError))
+            }
+
+            stream.remote_closed = true;
+        } else {
+            if id % 2 == 0 {
+                return Err(H2Error::Connection(ErrorCode::ProtocolError))
+            }
+
+            if id <= self.last_stream_id {
+                return Err(H2Error::Connection(ErrorCode::StreamClosed))
+            }
+
+            self.last_stream_id = id;
+
+            if self.goaway_received || self.streams.len() >= MAX_CONCURRENT_STREAMS {
+                return Err(H2Error::Stream(id, ErrorCode::RefusedStream))
+            }
+
+            let mut stream = self.stream(end_stream);
+            stream.headers = headers;
+            self.streams.insert(id, stream);
+        }
+
+        if end_stream {
+            self.ready(id)?;
+        }
+
+        Ok(())
+    }
+    fn on_window_update(&mut self, id: u32, payload: &[u8]) -> H2Result<()> {
+        if payload.len() != 4 {
+            return Err(H2Error::Connection(ErrorCode::FrameSizeError))
+        }
+
+        let increment = (read_u32(payload) & 0x7fff_ffff) as i64;
+
+        if id == 0 {
+            if increment == 0 {
+                return Err(H2Error::Connection(ErrorCode::ProtocolError))
+            }
+
+            self.send_window += increment;
+
+            if self.send_window > MAX_WINDOW {
+                return Err(H2Error::Connection(ErrorCode::FlowControlError))
+            }
+
+            return Ok(())
+        }
+
+        if increment == 0 {
+            return Err(H2Error::Stream(id, ErrorCode::ProtocolError))
+        }
+
+        match self.streams.get_mut(&id) {
+            Some(stream) => {
+                stream.send_window += increment;
+
+                if stream.send_window > MAX_WINDOW {
+                    return Err(H2Error::Stream(id, ErrorCode::FlowControlError))
+                }
+            },
+            None if id > self.last_stream_id => return Err(H2Error::Connection(ErrorCode::ProtocolError)),
+            None => {},
+        }
+
+        Ok(())
+    }
+
+    fn apply_settings(&mut self, payload: &[u8]) -> H2Result<()> {
+        for setting in payload.chunks(6) {
+            let id = (setting[0] as u16) << 8 | setting[1] as u16;
+            let value = read_u32(&setting[2..]);
+
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => {
+                    return Err(H2Error::Connection(ErrorCode::ProtocolError))
+                },
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+
+                    if value > MAX_WINDOW {
+                        return Err(H2Error::Connection(ErrorCode::FlowControlError))
+                    }
+
+                    let delta = value - self.initial_window;
+                    self.initial_window = value;
+
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+
+                        if stream.send_window > MAX_WINDOW {
+                            return Err(H2Error::Connection(ErrorCode::FlowControlError))
+                        }
+                    }
+                },
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if value < MAX_FRAME_SIZE as u32 || value > 16_777_215 {
+                        return Err(H2Error::Connection(ErrorCode::ProtocolError))
+                    }
+
+                    self.max_frame_size = value as usize;
+                },
+                //只输出字面量，不关心对端的动态表大小
+                SETTINGS_HEADER_TABLE_SIZE | SETTINGS_MAX_CONCURRENT_STREAMS | SETTINGS_MAX_HEADER_LIST_SIZE => {},
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 流收齐后生成请求放进 ready，数据交出去后归还占用的连接窗口
+    fn ready(&mut self, id: u32) -> H2Result<()> {
+        let (fields, data, received) = match self.streams.get_mut(&id) {
+            Some(stream) => (
+                mem::take(&mut stream.headers),
+                mem::take(&mut stream.data),
+                mem::take(&mut stream.received),
+            ),
+            None => return Ok(()),
+        };
+
+        self.refund(received);
+
+        let mut method = None;
+        let mut path = None;
+        let mut authority = None;
+        let mut headers: HashMap<String, String> = HashMap::new();
+
+        for (name, value) in fields {
+            match name.as_str() {
+                ":method" => method = Some(value),
+                ":path" => path = Some(value),
+                ":authority" => authority = Some(value),
+                ":scheme" => {},
+                name if name.starts_with(':') => return Err(H2Error::Stream(id, ErrorCode::ProtocolError)),
+                name => {
+                    let separator = if name == "cookie" { "; " } else { ", " };
+                    let entry = headers.entry(name.to_owned()).or_default();
+
+                    if !entry.is_empty() {
+                        entry.push_str(separator);
+                    }
+                    entry.push_str(&value);
+                }
+            }
+        }
+
+        let (method, path) = match (method, path) {
+            (Some(method), Some(path)) => (method, path),
+            _ => return Err(H2Error::Stream(id, ErrorCode::ProtocolError)),
+        };
+
+        if let Some(authority) = authority {
+            headers.entry("host".to_owned()).or_insert(authority);
+        }
+
+        let request = Request::new(method.parse().unwrap(), path, headers, self.remote_addr, data);
+
+        if let Some(stream) = self.streams.get_mut(&id) {
+            stream.head = request.method == Method::Head;
+        }
+
+        self.ready.push((id, request));
+
+        Ok(())
+    }
+
+    fn respond(&mut self, id: u32, mut response: Response) {
+        //事件流的响应体没有结尾，HTTP/2 的流还不支持，关闭事件流并返回 501，不让推送的事件一直缓存
+        if let Some(stream) = response.event_stream.take() {
+            stream.close();
+            response = Response::empty(501);
+            response.from_text("Server-Sent Events are not supported over HTTP/2").unwrap();
+        }
+
+        if self.streams.get(&id).is_some_and(|stream| stream.head) {
+            response.set_head(true);
+        }
+
+        let mut headers = vec![
+            (":status".to_owned(), response.status_code.0.to_string()),
+            ("date".to_owned(), HTTPDate::new().to_string()),
+            ("server".to_owned(), "Webserver".to_owned()),
+        ];
+
+        if let Some(data_length) = response.data_length {
+            if http::has_content_length(response.status_code.0) {
+                headers.push(("content-length".to_owned(), data_length.to_string()));
+            }
+        }
+
+        for (key, value) in response.headers {
+            let key = key.to_lowercase();
+
+            match key.as_str() {
+                "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade" | "content-length" => continue,
+                _ => headers.push((key, value)),
+            }
+        }
+
+        let mut block = Vec::new();
+        self.encoder.encode(&headers, &mut block);
+
+        let end_stream = response.data.is_empty();
+        let mut chunks = block.chunks(self.max_frame_size).peekable();
+        let mut first = true;
+
+        while let Some(chunk) = chunks.next() {
+            let mut flags = if chunks.peek().is_none() { FLAG_END_HEADERS } else { 0 };
+
+            if first {
+                if end_stream {
+                    flags |= FLAG_END_STREAM;
+                }
+                write_frame(&mut self.out, HEADERS, flags, id, chunk);
+                first = false;
+            } else {
+                write_frame(&mut self.out, CONTINUATION, flags, id, chunk);
+            }
+        }
+
+        if end_stream {
+            self.remove(id);
+        } else if let Some(stream) = self.streams.get_mut(&id) {
+            stream.body = Some(response.data);
+        }
+    }
+
+    /// 在连接和流的发送窗口内尽量发送待发的响应数据
+    fn flush(&mut self) {
+        let ids: Vec<u32> = self.streams.iter()
+            .filter(|&(_, stream)| stream.body.is_some())
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in ids {
+            let done = {
+                let out = &mut self.out;
+                let stream = self.streams.get_mut(&id).unwrap();
+                let body = stream.body.as_ref().unwrap();
+
+                loop {
+                    let remaining = body.len() - stream.sent;
+                    let window = cmp::min(self.send_window, stream.send_window);
+
+                    if window <= 0 {
+                        break false;
+                    }
+
+                    let size = cmp::min(cmp::min(remaining, self.max_frame_size), window as usize);
+                    let end = size == remaining;
+
+                    write_frame(out, DATA, if end { FLAG_END_STREAM } else { 0 }, id, &body[stream.sent..stream.sent + size]);
+
+                    stream.sent += size;
+                    stream.send_window -= size as i64;
+                    self.send_window -= size as i64;
+
+                    if end {
+                        break true;
+                    }
+                }
+            };
+
+            if done {
+                self.remove(id);
+            }
+        }
+    }
+}
+
+fn unpad<'a>(header: &FrameHeader, payload: &'a [u8]) -> H2Result<&'a [u8]> {
+    if !header.has(FLAG_PADDED) {
+        return Ok(payload)
+    }
+
+    if payload.is_empty() {
+        return Err(H2Error::Connection(ErrorCode::FrameSizeError))
+    }
+
+    let pad = payload[0] as usize;
+
+    if pad >= payload.len() {
+        return Err(H2Error::Connection(ErrorCode::ProtocolError))
+    }
+
+    Ok(&payload[1..payload.len() - pad])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::Method;
+
+    fn headers(stream_id: u32, flags: u8, path: &str) -> Vec<u8> {
+        let list = vec![
+            (":method".to_owned(), "POST".to_owned()),
+            (":scheme".to_owned(), "http".to_owned()),
+            (":path".to_owned(), path.to_owned()),
+            (":authority".to_owned(), "example.com".to_owned()),
+            ("cookie".to_owned(), "a=1".to_owned()),
+            ("cookie".to_owned(), "b=2".to_owned()),
+        ];
+
+        let mut block = Vec::new();
+        Encoder::new().encode(&list, &mut block);
+
+        let mut out = Vec::new();
+        write_frame(&mut out, HEADERS, FLAG_END_HEADERS | flags, stream_id, &block);
+        out
+    }
+
+    fn data(stream_id: u32, flags: u8, len: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_frame(&mut out, DATA, flags, stream_id, &vec![b'x'; len]);
+        out
+    }
+
+    fn connect() -> (State, Vec<u8>) {
+        let mut input = PREFACE.to_vec();
+        write_settings(&mut input, &[]);
+        (State::new(), input)
+    }
+
+    //按顺序列出输出里每个帧的类型和流 id
+    fn frames(out: &[u8]) -> Vec<(u8, u32)> {
+        let mut frames = Vec::new();
+        let mut pos = 0;
+
+        while pos < out.len() {
+            let header = FrameHeader::parse(&out[pos..]);
+            frames.push((header.kind, header.stream_id));
+            pos += HEADER_LEN + header.length;
+        }
+
+        frames
+    }
+
+    #[test]
+    fn request_from_header_block() {
+        let (mut state, mut input) = connect();
+        input.extend_from_slice(&headers(1, FLAG_END_STREAM, "/a?x=1"));
+
+        assert_eq!(state.read_frames(&input), input.len());
+        assert_eq!(frames(&state.out), vec![(SETTINGS, 0), (WINDOW_UPDATE, 0), (SETTINGS, 0)]);
+
+        let (id, request) = state.ready.pop().unwrap();
+        assert_eq!(id, 1);
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.path(), "/a?x=1");
+        assert_eq!(request.get_header("host"), Some("example.com".to_owned()));
+        assert_eq!(request.get_header("cookie"), Some("a=1; b=2".to_owned()));
+    }
+
+    #[test]
+    fn partial_frame_is_kept() {
+        let (mut state, mut input) = connect();
+        let consumed = input.len();
+        let frame = headers(1, FLAG_END_STREAM, "/");
+        input.extend_from_slice(&frame[..frame.len() - 1]);
+
+        assert_eq!(state.read_frames(&input), consumed);
+        assert!(state.ready.is_empty());
+        assert!(!state.goaway_sent);
+    }
+
+    #[test]
+    fn bad_preface() {
+        let mut state = State::new();
+
+        assert_eq!(state.read_frames(b"GET / HTTP/1.1\r\n\r\n"), 18);
+        assert!(state.goaway_sent);
+        assert_eq!(frames(&state.out).last(), Some(&(GOAWAY, 0)));
+    }
+
+    #[test]
+    fn window_refunded_when_body_is_handed_over() {
+        let (mut state, mut input) = connect();
+        input.extend_from_slice(&headers(1, 0, "/upload"));
+        input.extend_from_slice(&data(1, 0, 1000));
+        state.read_frames(&input);
+        state.out.clear();
+
+        //数据还在缓存里，窗口不归还
+        assert!(state.ready.is_empty());
+        assert_eq!(state.recv_window, CONN_WINDOW - 1000);
+        assert_eq!(state.streams[&1].recv_window, STREAM_WINDOW - 1000);
+        assert!(state.out.is_empty());
+
+        state.read_frames(&data(1, FLAG_END_STREAM, 10));
+
+        let (_, mut request) = state.ready.pop().unwrap();
+        assert_eq!(request.data().len(), 1010);
+        assert_eq!(state.recv_window, CONN_WINDOW);
+        assert_eq!(frames(&state.out), vec![(WINDOW_UPDATE, 0)]);
+        assert_eq!(read_u32(&state.out[HEADER_LEN..]), 1010);
+    }
+
+    #[test]
+    fn body_larger_than_stream_window() {
+        let (mut state, mut input) = connect();
+        input.extend_from_slice(&headers(1, 0, "/upload"));
+
+        for _ in 0..STREAM_WINDOW as usize / MAX_FRAME_SIZE {
+            input.extend_from_slice(&data(1, 0, MAX_FRAME_SIZE));
+        }
+
+        state.read_frames(&input);
+
+        //先响应 413 再重置流，缓存的数据归还给连接窗口
+        let frames = frames(&state.out);
+        assert_eq!(&frames[frames.len() - 3..], &[(HEADERS, 1), (WINDOW_UPDATE, 0), (RST_STREAM, 1)]);
+        assert!(state.ready.is_empty());
+        assert!(state.streams.is_empty());
+        assert_eq!(state.recv_window, CONN_WINDOW);
+    }
+
+    #[test]
+    fn event_stream_is_rejected() {
+        let (mut state, mut input) = connect();
+        input.extend_from_slice(&headers(1, FLAG_END_STREAM, "/events"));
+        state.read_frames(&input);
+        state.ready.pop().unwrap();
+        state.out.clear();
+
+        let mut response = Response::empty(200);
+        let stream = response.event_stream();
+        state.respond(1, response);
+
+        let header = FrameHeader::parse(&state.out);
+        assert_eq!((header.kind, header.stream_id), (HEADERS, 1));
+
+        let fields = Decoder::new(4096).decode(&state.out[HEADER_LEN..HEADER_LEN + header.length]).unwrap();
+        assert_eq!(fields[0], (":status".to_owned(), "501".to_owned()));
+        assert!(stream.is_closed());
+        assert!(stream.comment("ping").is_err());
+    }
+
+    #[test]
+    fn stream_window_exceeded() {
+        let (mut state, mut input) = connect();
+        input.extend_from_slice(&headers(1, 0, "/upload"));
+        state.read_frames(&input);
+        state.streams.get_mut(&1).unwrap().recv_window = 100;
+        state.out.clear();
+
+        state.read_frames(&data(1, 0, 101));
+
+        assert_eq!(frames(&state.out), vec![(WINDOW_UPDATE, 0), (RST_STREAM, 1)]);
+        assert_eq!(read_u32(&state.out[HEADER_LEN * 2 + 4..]), ErrorCode::FlowControlError as u32);
+        assert_eq!(state.recv_window, CONN_WINDOW);
+        assert!(!state.goaway_sent);
+    }
+
+    #[test]
+    fn connection_window_exceeded() {
+        let (mut state, mut input) = connect();
+        input.extend_from_slice(&headers(1, 0, "/upload"));
+        state.read_frames(&input);
+        state.recv_window = 100;
+
+        state.read_frames(&data(1, 0, 101));
+
+        assert!(state.goaway_sent);
+    }
+
+    #[test]
+    fn data_on_closed_stream_is_refunded() {
+        let (mut state, mut input) = connect();
+        input.extend_from_slice(&headers(1, FLAG_END_STREAM, "/"));
+        state.read_frames(&input);
+        state.out.clear();
+
+        state.read_frames(&data(1, 0, 100));
+
+        assert_eq!(frames(&state.out), vec![(WINDOW_UPDATE, 0), (RST_STREAM, 1)]);
+        assert_eq!(state.recv_window, CONN_WINDOW);
+    }
+
+    #[test]
+    fn padded_frames() {
+        let header = FrameHeader::parse(&[0, 0, 6, DATA, FLAG_PADDED, 0, 0, 0, 1]);
+
+        assert_eq!(unpad(&header, &[2, b'a', b'b', b'c', 0, 0]).ok(), Some(&b"abc"[..]));
+        assert!(unpad(&header, &[6, 0, 0, 0, 0, 0]).is_err());
+        assert!(unpad(&header, &[]).is_err());
+    }
+
+    #[test]
+    fn upgrade_settings_header() {
+        assert_eq!(base64url_decode("AAMAAABkAAQAAP__"), Some(vec![0, 3, 0, 0, 0, 100, 0, 4, 0, 0, 255, 255]));
+        assert_eq!(base64url_decode("AA*"), None);
+    }
+}
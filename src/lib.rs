@@ -6,11 +6,19 @@ extern crate httparse;
 extern crate serde_json;
 extern crate chrono;
 extern crate url;
+extern crate regex;
 
 pub mod connection;
 pub mod server;
+pub mod udp_server;
 pub mod error;
 pub mod app;
 pub mod stream_data;
+pub mod buffer;
 pub mod util;
-pub mod http;
\ No newline at end of file
+pub mod codec;
+pub mod executor;
+pub mod stats;
+pub mod http;
+#[cfg(feature = "h2c")]
+pub mod http2;
\ No newline at end of file
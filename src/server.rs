@@ -1,29 +1,127 @@
-use std::thread;
-use std::result::Result;
-use std::net::ToSocketAddrs;
 use std::collections::HashMap;
 use std::sync::mpsc::TryRecvError;
-use std::io::Write;
 use std::rc::Rc;
 use std::io::{self, ErrorKind};
-use std::error::Error;
-use std::fmt::{self, Formatter};
 use std::convert::From;
 use std::net::Shutdown;
-use mio::net::{TcpListener, TcpStream};
-use mio::deprecated::TryRead;
+use mio::net::TcpListener;
 use mio::channel::{self, Receiver, Sender};
 use mio::{Token, Ready, PollOpt, Poll, Events, Event, Evented};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::cmp;
 use error::MioResult;
-use util::threadpool::Pool;
+use util::threadpool::{Pool, PoolHandle, Scheduler, Priority, CoDel, ShutdownPolicy, ShutdownReport};
+use util::buffer_pool::BufferPool;
+use util::sync::lock;
 use stream_data::StreamData;
-use connection::{Connection, ConnEvent};
+use connection::{Connection, ConnEvent, ConnInfo, CloseReason, Shared};
+use executor::{Executor, Spawner};
+use stats::ServerStats;
+use num_cpus;
 
 const SERVER: Token = Token(0);
 const CHANNEL: Token = Token(1);
+const EXECUTOR: Token = Token(2);
 
-pub type Handle = Box<Fn(Arc<Mutex<StreamData>>) + Send + Sync + 'static>;
+pub type Handle = Box<dyn Fn(&mut StreamData) + Send + Sync + 'static>;
+
+/// 请求的执行方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dispatch {
+    /// 交给线程池执行
+    Pool,
+    /// 直接在事件循环线程执行，只适合不会阻塞的快速处理
+    Inline,
+    /// 按优先级交给线程池执行，Pool 相当于 Priority(Priority::Normal)
+    Priority(Priority),
+}
+
+/// 读到数据后决定执行方式，在事件循环线程上调用，应尽快返回
+pub type Classify = Box<dyn Fn(&StreamData) -> Dispatch + Send + Sync + 'static>;
+
+/// 线程池过载时代替 Handle 在事件循环线程上调用，写出拒绝响应，默认直接关闭连接
+pub type Reject = Box<dyn Fn(&mut StreamData) + Send + Sync + 'static>;
+
+pub type AcceptHook = Box<dyn Fn(&ConnInfo) + Send + Sync + 'static>;
+pub type CloseHook = Box<dyn Fn(&ConnInfo, CloseReason) + Send + Sync + 'static>;
+pub type ErrorHook = Box<dyn Fn(&ConnInfo, &io::Error) + Send + Sync + 'static>;
+
+/// 连接生命周期回调，都在事件循环线程上调用，应尽快返回
+#[derive(Default)]
+pub struct Hooks {
+    pub on_accept: Option<AcceptHook>,
+    pub on_close: Option<CloseHook>,
+    pub on_error: Option<ErrorHook>,
+}
+
+struct ShutdownState {
+    requested: bool,
+    senders: Vec<Sender<ConnEvent>>,
+    report: Option<ShutdownReport>,
+}
+
+/// 关闭服务器的句柄，可以在任意线程调用
+///
+/// 绑定之前调用 `shutdown` 也有效，服务器启动后会立即退出。
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<Mutex<ShutdownState>>,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> ShutdownHandle {
+        ShutdownHandle {
+            state: Arc::new(Mutex::new(ShutdownState {
+                requested: false,
+                senders: Vec::new(),
+                report: None,
+            })),
+        }
+    }
+
+    pub fn shutdown(&self) {
+        let mut state = lock(&self.state);
+        state.requested = true;
+
+        for tx in state.senders.drain(..) {
+            let _ = tx.send(ConnEvent::Shutdown);
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        lock(&self.state).requested
+    }
+
+    /// 服务器退出时线程池的关闭结果，run 返回之前为 None
+    pub fn report(&self) -> Option<ShutdownReport> {
+        lock(&self.state).report
+    }
+
+    fn finish(&self, report: ShutdownReport) {
+        lock(&self.state).report = Some(report);
+    }
+
+    fn bind(&self, tx: Sender<ConnEvent>) {
+        let mut state = lock(&self.state);
+
+        if state.requested {
+            let _ = tx.send(ConnEvent::Shutdown);
+        } else {
+            state.senders.push(tx);
+        }
+    }
+}
+
+//工作窃取的线程数固定，每个 CPU 一个线程就够了，不像共用队列那样按负载增加
+fn thread_pool(scheduler: Scheduler) -> Pool {
+    let cpus = num_cpus::get();
+
+    match scheduler {
+        Scheduler::Shared => Pool::with_scheduler(scheduler, Some("sunflower-worker"), cpus, cpus * 16),
+        Scheduler::WorkStealing => Pool::with_scheduler(scheduler, Some("sunflower-worker"), cpus, cpus),
+    }
+}
 
 pub struct Server {
     poll: Poll,
@@ -34,7 +132,18 @@ pub struct Server {
     tx: Sender<ConnEvent>,
     rx: Receiver<ConnEvent>,
     thread_pool: Rc<Pool>,
+    buffers: BufferPool,
     handle: Arc<Handle>,
+    classify: Arc<Classify>,
+    reject: Arc<Reject>,
+    shared: Option<Rc<Shared>>,
+    executor: Executor,
+    hooks: Hooks,
+    idle_timeout: Option<Duration>,
+    drain_timeout: Option<Duration>,
+    shutdown: ShutdownHandle,
+    stopping: bool,
+    stats: ServerStats,
 }
 
 impl Server {
@@ -42,6 +151,8 @@ impl Server {
         let l = TcpListener::bind(&url.parse().unwrap()).unwrap();
         let (tx, rx) = channel::channel::<ConnEvent>();
         let poll = Poll::new().unwrap();
+        let shutdown = ShutdownHandle::new();
+        shutdown.bind(tx.clone());
         let server = Server {
             poll,
             token: 4,
@@ -50,15 +161,145 @@ impl Server {
             events: Events::with_capacity(1024),
             tx,
             rx,
-            thread_pool: Rc::new(Pool::new()),
+            thread_pool: Rc::new(thread_pool(Scheduler::Shared)),
+            buffers: BufferPool::new(),
             handle: Arc::new(Box::new(|_| {})),
+            classify: Arc::new(Box::new(|_| Dispatch::Pool)),
+            reject: Arc::new(Box::new(|stream_data| stream_data.close())),
+            shared: None,
+            executor: Executor::new(),
+            hooks: Hooks::default(),
+            idle_timeout: None,
+            drain_timeout: None,
+            shutdown: shutdown,
+            stopping: false,
+            stats: ServerStats::new(),
         };
         return Ok(server)
     }
 
+    /// 提交 Future 到事件循环的执行器
+    pub fn spawner(&self) -> Spawner {
+        self.executor.spawner()
+    }
+
+    /// 线程池的句柄，处理函数可以把其他任务提交到同一个线程池
+    pub fn pool_handle(&self) -> PoolHandle {
+        self.thread_pool.handle()
+    }
+
+    /// 连接读写缓冲区共用的缓冲区池，可以查看统计
+    pub fn buffer_pool(&self) -> BufferPool {
+        self.buffers.clone()
+    }
+
+    /// 替换缓冲区池，需要在 run 之前调用
+    pub fn set_buffer_pool(&mut self, buffers: BufferPool) {
+        self.buffers = buffers;
+    }
+
+    /// 设置执行方式的判断，默认全部交给线程池
+    pub fn set_classify(&mut self, classify: Classify) {
+        self.classify = Arc::new(classify);
+    }
+
+    /// 设置线程池过载时的拒绝处理
+    pub fn set_reject(&mut self, reject: Reject) {
+        self.reject = Arc::new(reject);
+    }
+
+    /// 线程池的调度方式，默认共用一个队列
+    ///
+    /// 会重新创建线程池，需要在 run 和其他线程池设置之前调用。
+    pub fn set_scheduler(&mut self, scheduler: Scheduler) {
+        if self.thread_pool.scheduler() != scheduler {
+            self.thread_pool = Rc::new(thread_pool(scheduler));
+        }
+    }
+
+    /// 给 priority 和更高的优先级预留线程，低优先级的请求再多也不会占满线程池
+    pub fn set_reserved(&mut self, priority: Priority, workers: usize) {
+        self.thread_pool.reserve(priority, workers);
+    }
+
+    /// 线程池排队任务数上限，超过后新请求交给 Reject 处理，None 表示不限制
+    pub fn set_max_queue(&mut self, max: Option<usize>) {
+        self.thread_pool.set_max_queue(max);
+    }
+
+    /// 按排队时间拒绝请求（CoDel），None 表示关闭
+    pub fn set_codel(&mut self, codel: Option<CoDel>) {
+        self.thread_pool.set_codel(codel);
+    }
+
+    pub fn on_accept<F>(&mut self, hook: F)
+        where F: Fn(&ConnInfo) + Send + Sync + 'static
+    {
+        self.hooks.on_accept = Some(Box::new(hook));
+    }
+
+    pub fn on_close<F>(&mut self, hook: F)
+        where F: Fn(&ConnInfo, CloseReason) + Send + Sync + 'static
+    {
+        self.hooks.on_close = Some(Box::new(hook));
+    }
+
+    pub fn on_error<F>(&mut self, hook: F)
+        where F: Fn(&ConnInfo, &io::Error) + Send + Sync + 'static
+    {
+        self.hooks.on_error = Some(Box::new(hook));
+    }
+
+    pub fn set_hooks(&mut self, hooks: Hooks) {
+        self.hooks = hooks;
+    }
+
+    /// 连接没有读写超过这个时间就关闭，正在处理或有待写数据的连接不算空闲
+    ///
+    /// 长轮询、SSE 这类长时间不读写的连接需要比超时时间更频繁地发送数据。
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// 关闭时等待线程池里的请求处理完并写回响应的最长时间，None 表示丢弃排队的请求直接关闭
+    pub fn set_drain_timeout(&mut self, timeout: Option<Duration>) {
+        self.drain_timeout = timeout;
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// 使用外部创建的关闭句柄，可以在 run 之前拿到句柄
+    pub fn set_shutdown_handle(&mut self, shutdown: ShutdownHandle) {
+        shutdown.bind(self.tx.clone());
+        self.shutdown = shutdown;
+    }
+
+    /// 运行统计，可以在任意线程调用 snapshot
+    pub fn stats(&self) -> ServerStats {
+        self.stats.clone()
+    }
+
+    /// 使用外部创建的统计，需要在 run 之前调用
+    pub fn set_stats(&mut self, stats: ServerStats) {
+        self.stats = stats;
+    }
+
     pub fn run(&mut self, handle: Handle) -> MioResult<()> {
 
         self.handle = Arc::new(handle);
+        self.stats.set_pool(self.thread_pool.monitor());
+
+        self.shared = Some(Rc::new(Shared {
+            tx: self.tx.clone(),
+            thread_pool: self.thread_pool.clone(),
+            buffers: self.buffers.clone(),
+            stats: self.stats.clone(),
+            handle: self.handle.clone(),
+            classify: self.classify.clone(),
+            reject: self.reject.clone(),
+        }));
 
         //listener事件注册
         self.poll.register(&self.listener, SERVER, Ready::readable(), PollOpt::level())?;
@@ -66,25 +307,29 @@ impl Server {
         //数据读写事件注册 通道
         self.poll.register(&self.rx, CHANNEL, Ready::readable(), PollOpt::level())?;
 
+        //Future 执行器唤醒
+        self.poll.register(&self.executor, EXECUTOR, Ready::readable(), PollOpt::edge())?;
+
         let mut events = Events::with_capacity(128);
+        let mut last_sweep = Instant::now();
         loop {
-            self.poll.poll(&mut events, None)?;
+            //有空闲超时时定期醒来检查
+            let tick = self.idle_timeout.map(|timeout| cmp::min(timeout, Duration::from_secs(1)));
+
+            self.poll.poll(&mut events, tick)?;
+
+            let started = Instant::now();
 
             for event in &events {
                 match event.token() {
                     SERVER => {//建立连接
-                        self.token = self.token + 1;
-                        let new_token = Token::from(self.token);
-                        let (mut tcp_stream, _) = self.listener.accept().unwrap();
-                        self.poll.register(
-                            &tcp_stream, new_token,
-                            Ready::readable() | Ready::hup(),
-                            PollOpt::edge() | PollOpt::oneshot()
-                        )?;
-                        self.conns.insert(new_token, Connection::new(new_token, tcp_stream, self.tx.clone(), self.thread_pool.clone(), self.handle.clone()));
+                        self.accept()?;
                     },
                     CHANNEL => {//StreamData读写注册
-                        self.channel();
+                        self.channel()?;
+                    },
+                    EXECUTOR => {//轮询被唤醒的 Future
+                        self.executor.run_ready();
                     },
                     token => {//接入tcp_stream事件处理
                         self.connect(event, token);
@@ -92,33 +337,191 @@ impl Server {
 
                 };
             }
+
+            if self.stopping {
+                self.stop();
+                return Ok(())
+            }
+
+            if let Some(tick) = tick {
+                if last_sweep.elapsed() >= tick {
+                    last_sweep = Instant::now();
+                    self.sweep();
+                }
+            }
+
+            self.stats.loop_iteration(started.elapsed());
+        }
+    }
+
+    fn accept(&mut self) -> MioResult<()> {
+        let tcp_stream = match self.listener.accept() {
+            Ok((tcp_stream, _)) => tcp_stream,
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+            //文件描述符用完等，等下次可读再试
+            Err(err) => {
+                self.stats.error(err.kind());
+                return Ok(())
+            }
+        };
+
+        self.token = self.token + 1;
+        let new_token = Token::from(self.token);
+
+        self.poll.register(
+            &tcp_stream, new_token,
+            Ready::readable() | Ready::hup(),
+            PollOpt::edge() | PollOpt::oneshot()
+        )?;
+
+        let shared = match self.shared {
+            Some(ref shared) => shared.clone(),
+            None => return Ok(()),
+        };
+
+        let conn = Connection::new(new_token, tcp_stream, shared);
+
+        self.stats.accepted();
+
+        if let Some(ref hook) = self.hooks.on_accept {
+            hook(&conn.info);
+        }
+
+        self.conns.insert(new_token, conn);
+
+        Ok(())
+    }
+
+    /// 关闭空闲超时的连接
+    fn sweep(&mut self) {
+        let timeout = match self.idle_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+
+        let tokens: Vec<Token> = self.conns.iter()
+            .filter(|&(_, conn)| conn.is_idle() && conn.last_active.elapsed() >= timeout)
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in tokens {
+            if let Some(conn) = self.conns.get_mut(&token) {
+                conn.close(CloseReason::Timeout, None);
+            }
+
+            self.close(token);
+        }
+    }
+
+    /// 停止接受连接并关闭所有连接
+    fn stop(&mut self) {
+        let _ = self.poll.deregister(&self.listener);
+
+        //等线程池处理完，继续处理事件把已经完成的响应写出去再关闭连接
+        let report = match self.drain_timeout {
+            Some(timeout) => {
+                let deadline = Instant::now() + timeout;
+                let report = self.thread_pool.shutdown(ShutdownPolicy::Drain, deadline);
+                self.flush(deadline);
+                report
+            }
+            None => self.thread_pool.shutdown(ShutdownPolicy::Discard, Instant::now()),
+        };
+
+        self.shutdown.finish(report);
+
+        let tokens: Vec<Token> = self.conns.keys().cloned().collect();
+
+        for token in tokens {
+            if let Some(conn) = self.conns.get_mut(&token) {
+                conn.close(CloseReason::Shutdown, None);
+            }
+
+            self.close(token);
+        }
+    }
+
+    /// 处理剩下的事件，直到所有连接都空闲（没有在线程池里的请求和待写数据）或者到截止时间
+    fn flush(&mut self, deadline: Instant) {
+        let mut events = Events::with_capacity(128);
+
+        loop {
+            if self.channel().is_err() || self.conns.values().all(|conn| conn.is_idle()) {
+                return
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                return
+            }
+
+            if self.poll.poll(&mut events, Some(cmp::min(deadline - now, Duration::from_millis(10)))).is_err() {
+                return
+            }
+
+            for event in &events {
+                match event.token() {
+                    SERVER | CHANNEL => {},
+                    EXECUTOR => {
+                        self.executor.run_ready();
+                    },
+                    token => {
+                        self.connect(event, token);
+                    }
+                };
+            }
+        }
+    }
+
+    /// 移除连接并调用关闭回调
+    fn close(&mut self, token: Token) {
+        if let Some(conn) = self.conns.remove(&token) {
+            let _ = conn.deregister(&self.poll);
+            let _ = conn.tcp_stream.shutdown(Shutdown::Both);
+
+            self.stats.closed();
+
+            if let Some(ref err) = conn.error {
+                self.stats.error(err.kind());
+            }
+
+            if let (Some(ref err), Some(ref hook)) = (conn.error.as_ref(), self.hooks.on_error.as_ref()) {
+                hook(&conn.info, err);
+            }
+
+            if let Some(ref hook) = self.hooks.on_close {
+                hook(&conn.info, conn.reason);
+            }
         }
     }
 
     ///
-    /// StreamData读写事件注册
+    /// 处理完成和其他线程写入的数据
     fn channel(&mut self) -> MioResult<()> {
         loop {
             match self.rx.try_recv() {
                 Ok(event) => {
                     match event {
-                        ConnEvent::Write(token) => {
-                            if let Some(conn) = self.conns.get(&token) {
-                                conn.reregister(
-                                    &self.poll, token,
-                                    Ready::writable() | Ready::hup(),
-                                    PollOpt::edge() | PollOpt::oneshot()
-                                )?;
-                            }
+                        ConnEvent::Done(token, stream_data) => {
+                            let interest = match self.conns.get_mut(&token) {
+                                Some(conn) => conn.done(stream_data),
+                                //处理期间连接已经关闭，缓冲区还回池里
+                                None => {
+                                    self.buffers.put(stream_data.reader);
+                                    self.buffers.put(stream_data.writer);
+                                    continue;
+                                }
+                            };
+
+                            self.rearm(token, Some(interest));
                         },
-                        ConnEvent::Read(token) => {
-                            if let Some(conn) = self.conns.get(&token) {
-                                conn.reregister(
-                                    &self.poll, token,
-                                    Ready::readable() | Ready::hup(),
-                                    PollOpt::edge() | PollOpt::oneshot()
-                                )?;
-                            }
+                        ConnEvent::Write(token, data) => {
+                            let interest = self.conns.get_mut(&token).and_then(|conn| conn.write(data));
+                            self.rearm(token, interest);
+                        },
+                        ConnEvent::Shutdown => {
+                            self.stopping = true;
                         },
                     }
                 },
@@ -139,38 +542,59 @@ impl Server {
         Ok(())
     }
 
-    fn connect(&mut self, event: Event, token: Token) -> MioResult<()> {
+    fn connect(&mut self, event: Event, token: Token) {
 
         if event.readiness().is_hup() || event.readiness().is_error() {
-            if let Some(conn) = self.conns.remove(&token) {
-                self.poll.deregister(&conn.tcp_stream);
-                return Ok(())
+            if let Some(conn) = self.conns.get_mut(&token) {
+                if event.readiness().is_error() {
+                    let err = conn.tcp_stream.take_error().ok().and_then(|err| err);
+                    conn.close(CloseReason::Error, err);
+                } else {
+                    conn.close(CloseReason::Hup, None);
+                }
             }
+
+            self.close(token);
+            return
         }
 
-        let mut close = false;
+        let mut interest = None;
 
         if event.readiness().is_readable() {
             if let Some(conn) = self.conns.get_mut(&token) {
-                conn.reader();
-                close = conn.closing;
+                interest = conn.reader();
             }
         }
 
         if event.readiness().is_writable() {
             if let Some(conn) = self.conns.get_mut(&token) {
-                conn.writer();
-                close = conn.closing;
+                interest = Some(conn.writer());
             }
         }
 
-        if close {
-            if let Some(conn) = self.conns.remove(&token) {
-                conn.deregister(&self.poll)?;
-                conn.tcp_stream.shutdown(Shutdown::Both);
+        self.rearm(token, interest)
+    }
+
+    /// 连接需要关闭时关闭，否则按返回的事件重新注册；None 表示等线程池处理完再注册
+    ///
+    /// 重新注册失败只影响这个连接，关闭它而不是让事件循环退出。
+    fn rearm(&mut self, token: Token, interest: Option<Ready>) {
+        if let (Some(interest), Some(conn)) = (interest, self.conns.get_mut(&token)) {
+            if !conn.closing {
+                let result = conn.reregister(
+                    &self.poll, token,
+                    interest | Ready::hup(),
+                    PollOpt::edge() | PollOpt::oneshot()
+                );
+
+                if let Err(err) = result {
+                    conn.close(CloseReason::Error, Some(err));
+                }
             }
         }
 
-        Ok(())
+        if self.conns.get(&token).map_or(false, |conn| conn.closing) {
+            self.close(token);
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,189 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use util::threadpool::PoolMonitor;
+use util::sync::lock;
+
+/// 某一时刻的服务器统计
+#[derive(Debug, Clone)]
+pub struct StatsSnapshot {
+    /// 当前打开的连接数
+    pub open_connections: usize,
+    /// 累计接受的连接数
+    pub accepted: usize,
+    /// 最近一秒左右每秒接受的连接数
+    pub accepts_per_sec: f64,
+    /// 累计处理的请求数
+    pub requests: usize,
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+    /// 线程池排队的任务数
+    pub pool_queued: usize,
+    /// 线程池存活的线程数
+    pub pool_active: usize,
+    /// 线程池空闲等待的线程数
+    pub pool_waiting: usize,
+    /// 线程池过载被拒绝的请求数
+    pub pool_rejected: usize,
+    /// 处理请求时 panic 的次数
+    pub panics: usize,
+    /// 事件循环处理过的轮数
+    pub loop_iterations: u64,
+    /// 每轮处理事件的平均耗时，不含等待事件的时间
+    pub loop_latency_avg: Duration,
+    pub loop_latency_max: Duration,
+    /// 按错误类型统计的连接和 accept 错误
+    pub errors: HashMap<ErrorKind, usize>,
+}
+
+struct Rate {
+    start: Instant,
+    count: usize,
+    //上一个完整窗口的速率，第一个窗口结束前为 None
+    rate: Option<f64>,
+}
+
+struct Inner {
+    open_connections: AtomicUsize,
+    accepted: AtomicUsize,
+    accept_rate: Mutex<Rate>,
+    requests: AtomicUsize,
+    bytes_in: AtomicUsize,
+    bytes_out: AtomicUsize,
+    panics: AtomicUsize,
+    loop_iterations: AtomicU64,
+    loop_nanos: AtomicU64,
+    loop_max_nanos: AtomicU64,
+    errors: Mutex<HashMap<ErrorKind, usize>>,
+    pool: Mutex<Option<PoolMonitor>>,
+}
+
+/// 服务器运行统计，计数由事件循环更新，`snapshot` 可以在任意线程调用
+#[derive(Clone)]
+pub struct ServerStats {
+    inner: Arc<Inner>,
+}
+
+impl ServerStats {
+    pub fn new() -> ServerStats {
+        ServerStats {
+            inner: Arc::new(Inner {
+                open_connections: AtomicUsize::new(0),
+                accepted: AtomicUsize::new(0),
+                accept_rate: Mutex::new(Rate {
+                    start: Instant::now(),
+                    count: 0,
+                    rate: None,
+                }),
+                requests: AtomicUsize::new(0),
+                bytes_in: AtomicUsize::new(0),
+                bytes_out: AtomicUsize::new(0),
+                panics: AtomicUsize::new(0),
+                loop_iterations: AtomicU64::new(0),
+                loop_nanos: AtomicU64::new(0),
+                loop_max_nanos: AtomicU64::new(0),
+                errors: Mutex::new(HashMap::new()),
+                pool: Mutex::new(None),
+            }),
+        }
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let inner = &self.inner;
+
+        let iterations = inner.loop_iterations.load(Ordering::Relaxed);
+        let nanos = inner.loop_nanos.load(Ordering::Relaxed);
+
+        let (pool_queued, pool_active, pool_waiting, pool_rejected) = match *lock(&inner.pool) {
+            Some(ref pool) => (pool.queued(), pool.active(), pool.waiting(), pool.rejected()),
+            None => (0, 0, 0, 0),
+        };
+
+        StatsSnapshot {
+            open_connections: inner.open_connections.load(Ordering::Relaxed),
+            accepted: inner.accepted.load(Ordering::Relaxed),
+            accepts_per_sec: self.accepts_per_sec(),
+            requests: inner.requests.load(Ordering::Relaxed),
+            bytes_in: inner.bytes_in.load(Ordering::Relaxed),
+            bytes_out: inner.bytes_out.load(Ordering::Relaxed),
+            pool_queued: pool_queued,
+            pool_active: pool_active,
+            pool_waiting: pool_waiting,
+            pool_rejected: pool_rejected,
+            panics: inner.panics.load(Ordering::Relaxed),
+            loop_iterations: iterations,
+            loop_latency_avg: Duration::from_nanos(if iterations == 0 { 0 } else { nanos / iterations }),
+            loop_latency_max: Duration::from_nanos(inner.loop_max_nanos.load(Ordering::Relaxed)),
+            errors: lock(&inner.errors).clone(),
+        }
+    }
+
+    //窗口超过一秒还没更新说明最近没有新连接，按窗口内的数量重新算
+    fn accepts_per_sec(&self) -> f64 {
+        let rate = lock(&self.inner.accept_rate);
+        let elapsed = rate.start.elapsed();
+
+        match rate.rate {
+            Some(last) if elapsed < Duration::from_secs(1) => last,
+            _ => rate.count as f64 / cmp::max(elapsed, Duration::from_secs(1)).as_secs_f64(),
+        }
+    }
+
+    pub fn set_pool(&self, pool: PoolMonitor) {
+        *lock(&self.inner.pool) = Some(pool);
+    }
+
+    pub fn accepted(&self) {
+        self.inner.accepted.fetch_add(1, Ordering::Relaxed);
+        self.inner.open_connections.fetch_add(1, Ordering::Relaxed);
+
+        let mut rate = lock(&self.inner.accept_rate);
+        let elapsed = rate.start.elapsed();
+
+        if elapsed >= Duration::from_secs(1) {
+            rate.rate = Some(rate.count as f64 / elapsed.as_secs_f64());
+            rate.start = Instant::now();
+            rate.count = 0;
+        }
+
+        rate.count += 1;
+    }
+
+    pub fn closed(&self) {
+        self.inner.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn requests(&self, count: usize) {
+        self.inner.requests.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn bytes_in(&self, size: usize) {
+        self.inner.bytes_in.fetch_add(size, Ordering::Relaxed);
+    }
+
+    pub fn bytes_out(&self, size: usize) {
+        self.inner.bytes_out.fetch_add(size, Ordering::Relaxed);
+    }
+
+    pub fn panicked(&self) {
+        self.inner.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn error(&self, kind: ErrorKind) {
+        *lock(&self.inner.errors).entry(kind).or_insert(0) += 1;
+    }
+
+    /// 记录事件循环一轮的耗时
+    pub fn loop_iteration(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos() as u64;
+
+        self.inner.loop_iterations.fetch_add(1, Ordering::Relaxed);
+        self.inner.loop_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.inner.loop_max_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+}
+
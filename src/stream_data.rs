@@ -3,41 +3,56 @@ use std::io::Write;
 use std::net::SocketAddr;
 use std::str::FromStr;
 use error::MioResult;
-use std::cmp;
-use std::mem;
 use std::io::Result as IoResult;
+use connection::ConnWriter;
+use buffer::Buffer;
+#[cfg(feature = "h2c")]
+use http2::Session;
 
 pub struct StreamData {
-    pub reader: Vec<u8>,
-    pub writer: Vec<u8>,
+    pub reader: Buffer,
+    pub writer: Buffer,
     pub remote_addr: SocketAddr,
+    pub conn: Option<ConnWriter>,
+    pub closing: bool,
+    /// 这个连接上处理过的请求数
+    pub requests: usize,
+    #[cfg(feature = "h2c")]
+    pub h2: Option<Session>,
 }
 
 impl StreamData {
-    pub fn new(reader: Vec<u8>, writer: Vec<u8>) -> StreamData {
+    pub fn new(reader: Buffer, writer: Buffer) -> StreamData {
         StreamData {
             reader: reader,
             writer: writer,
             remote_addr: SocketAddr::from_str("0.0.0.0:0").unwrap(),
+            conn: None,
+            closing: false,
+            requests: 0,
+            #[cfg(feature = "h2c")]
+            h2: None,
         }
     }
 
     pub fn remote_addr(&self) -> SocketAddr {
         self.remote_addr
     }
+
+    pub fn conn(&self) -> Option<ConnWriter> {
+        self.conn.clone()
+    }
+
+    /// 写完待写数据后关闭连接
+    pub fn close(&mut self) {
+        self.closing = true;
+    }
 }
 
 impl Read for StreamData {
     #[inline]
     fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
-        let amt = cmp::min(buf.len(), self.reader.len());
-
-        let reader = mem::replace(&mut self.reader, Vec::new());
-        let (a, b) = reader.split_at(amt);
-        buf[..amt].copy_from_slice(a);
-        self.reader = b.to_vec();
-
-        Ok(amt)
+        self.reader.read(buf)
     }
 }
 
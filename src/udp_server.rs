@@ -0,0 +1,187 @@
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::mpsc::TryRecvError;
+use mio::net::UdpSocket;
+use mio::channel::{self, Receiver, Sender};
+use mio::{Token, Ready, PollOpt, Poll, Events};
+use error::{MioResult, MioError};
+use util::threadpool::Pool;
+
+const SOCKET: Token = Token(0);
+const CHANNEL: Token = Token(1);
+
+const MAX_DATAGRAM: usize = 65536;
+
+pub struct Datagram {
+    pub data: Vec<u8>,
+    pub remote_addr: SocketAddr,
+}
+
+/// 回复句柄，数据交给事件循环发送，可以在任意线程使用
+#[derive(Clone)]
+pub struct UdpResponder {
+    remote_addr: SocketAddr,
+    tx: Sender<(SocketAddr, Vec<u8>)>,
+}
+
+impl UdpResponder {
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    pub fn reply(&self, data: &[u8]) -> MioResult<()> {
+        self.send_to(self.remote_addr, data)
+    }
+
+    pub fn send_to(&self, addr: SocketAddr, data: &[u8]) -> MioResult<()> {
+        self.tx.send((addr, data.to_vec()))
+            .map_err(|_| MioError::Error("Udp server closed".to_owned()))
+    }
+}
+
+pub type UdpHandle = Box<dyn Fn(Datagram, UdpResponder) + Send + Sync + 'static>;
+
+pub struct UdpServer {
+    poll: Poll,
+    socket: UdpSocket,
+    tx: Sender<(SocketAddr, Vec<u8>)>,
+    rx: Receiver<(SocketAddr, Vec<u8>)>,
+    outgoing: VecDeque<(SocketAddr, Vec<u8>)>,
+    writable: bool,
+    thread_pool: Pool,
+    handle: Arc<UdpHandle>,
+}
+
+impl UdpServer {
+    pub fn new(url: &str) -> MioResult<UdpServer> {
+        let addr: SocketAddr = url.parse()
+            .map_err(|_| MioError::Error(format!("Invalid address: {}", url)))?;
+        let socket = UdpSocket::bind(&addr)?;
+        let (tx, rx) = channel::channel();
+
+        Ok(UdpServer {
+            poll: Poll::new()?,
+            socket: socket,
+            tx: tx,
+            rx: rx,
+            outgoing: VecDeque::new(),
+            writable: false,
+            thread_pool: Pool::new(),
+            handle: Arc::new(Box::new(|_, _| {})),
+        })
+    }
+
+    pub fn local_addr(&self) -> MioResult<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub fn run(&mut self, handle: UdpHandle) -> MioResult<()> {
+
+        self.handle = Arc::new(handle);
+
+        self.poll.register(&self.socket, SOCKET, Ready::readable(), PollOpt::edge())?;
+
+        //回复数据通道
+        self.poll.register(&self.rx, CHANNEL, Ready::readable(), PollOpt::level())?;
+
+        let mut events = Events::with_capacity(128);
+        loop {
+            self.poll.poll(&mut events, None)?;
+
+            for event in &events {
+                match event.token() {
+                    SOCKET => {
+                        if event.readiness().is_readable() {
+                            self.receive()?;
+                        }
+
+                        if event.readiness().is_writable() {
+                            self.flush()?;
+                        }
+                    },
+                    CHANNEL => {
+                        self.channel()?;
+                        self.flush()?;
+                    },
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// 边沿触发，读到 WouldBlock 为止，每个数据报交给线程池处理
+    fn receive(&mut self) -> MioResult<()> {
+        let mut buf = [0; MAX_DATAGRAM];
+
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((size, remote_addr)) => {
+                    let datagram = Datagram {
+                        data: buf[..size].to_vec(),
+                        remote_addr: remote_addr,
+                    };
+
+                    let responder = UdpResponder {
+                        remote_addr: remote_addr,
+                        tx: self.tx.clone(),
+                    };
+
+                    let handle = self.handle.clone();
+
+                    self.thread_pool.execute(move || {
+                        handle(datagram, responder);
+                    });
+                },
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                //ICMP 等错误只影响单个数据报，边沿触发，后面排队的数据报要继续读完
+                Err(_) => continue,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn channel(&mut self) -> MioResult<()> {
+        loop {
+            match self.rx.try_recv() {
+                Ok(datagram) => self.outgoing.push_back(datagram),
+                Err(TryRecvError::Empty) => break,
+                Err(err @ TryRecvError::Disconnected) => {
+                    return Err(io::Error::new(ErrorKind::ConnectionAborted, err).into())
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 发送队列里的回复，发不出去时等待可写
+    fn flush(&mut self) -> MioResult<()> {
+        while let Some((addr, data)) = self.outgoing.pop_front() {
+            match self.socket.send_to(&data, &addr) {
+                Ok(_) => {},
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                    self.outgoing.push_front((addr, data));
+
+                    if !self.writable {
+                        self.writable = true;
+                        self.poll.reregister(&self.socket, SOCKET, Ready::readable() | Ready::writable(), PollOpt::edge())?;
+                    }
+
+                    return Ok(())
+                },
+                //发送失败的数据报直接丢弃
+                Err(_) => {},
+            }
+        }
+
+        if self.writable {
+            self.writable = false;
+            self.poll.reregister(&self.socket, SOCKET, Ready::readable(), PollOpt::edge())?;
+        }
+
+        Ok(())
+    }
+}
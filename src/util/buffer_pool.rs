@@ -0,0 +1,291 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use buffer::Buffer;
+use super::sync::lock;
+
+/// 默认的容量等级
+const CLASSES: [usize; 4] = [1024, 4 * 1024, 16 * 1024, 64 * 1024];
+
+/// 每个等级默认最多缓存的空闲缓冲区数量
+const MAX_IDLE: usize = 1024;
+
+/// 缓冲区池的统计
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BufferPoolStats {
+    /// 从池里取到空闲缓冲区的次数
+    pub hits: usize,
+    /// 池里没有空闲缓冲区，新分配的次数
+    pub misses: usize,
+    /// 还回池里的次数
+    pub returned: usize,
+    /// 池满或容量太小被直接释放的次数
+    pub discarded: usize,
+    /// 超过最大等级被收缩的次数
+    pub shrunk: usize,
+    /// 当前空闲的缓冲区数量
+    pub idle: usize,
+    /// 当前空闲缓冲区占用的字节数
+    pub idle_bytes: usize,
+}
+
+struct Class {
+    size: usize,
+    idle: Mutex<Vec<Vec<u8>>>,
+}
+
+struct Inner {
+    classes: Vec<Class>,
+    max_idle: usize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    returned: AtomicUsize,
+    discarded: AtomicUsize,
+    shrunk: AtomicUsize,
+}
+
+/// 按容量分级的缓冲区池，连接的读写缓冲区从这里取出并在关闭时还回
+///
+/// 还回的缓冲区超过最大等级时先收缩到最大等级（高水位），避免偶尔的大请求让内存一直涨上去。
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Arc<Inner>,
+}
+
+impl BufferPool {
+    pub fn new() -> BufferPool {
+        BufferPool::with_classes(&CLASSES, MAX_IDLE)
+    }
+
+    /// 自定义容量等级和每个等级最多缓存的数量，等级会按从小到大排序
+    pub fn with_classes(sizes: &[usize], max_idle: usize) -> BufferPool {
+        let mut sizes = sizes.to_vec();
+        sizes.sort();
+        sizes.dedup();
+
+        BufferPool {
+            inner: Arc::new(Inner {
+                classes: sizes.into_iter().filter(|size| *size > 0).map(|size| Class {
+                    size: size,
+                    idle: Mutex::new(Vec::new()),
+                }).collect(),
+                max_idle: max_idle,
+                hits: AtomicUsize::new(0),
+                misses: AtomicUsize::new(0),
+                returned: AtomicUsize::new(0),
+                discarded: AtomicUsize::new(0),
+                shrunk: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// 取出一个容量不小于 size 的缓冲区
+    pub fn get(&self, size: usize) -> Buffer {
+        let inner = &self.inner;
+
+        let class = match inner.classes.iter().find(|class| class.size >= size) {
+            Some(class) => class,
+            //比最大等级还大的不走池
+            None => {
+                inner.misses.fetch_add(1, Ordering::Relaxed);
+                return Buffer::with_capacity(size)
+            }
+        };
+
+        if let Some(data) = lock(&class.idle).pop() {
+            inner.hits.fetch_add(1, Ordering::Relaxed);
+            return Buffer::from_vec(data)
+        }
+
+        inner.misses.fetch_add(1, Ordering::Relaxed);
+        Buffer::with_capacity(class.size)
+    }
+
+    /// 还回缓冲区，放进容量不超过它的最大等级
+    pub fn put(&self, buffer: Buffer) {
+        let inner = &self.inner;
+        let mut data = buffer.into_inner();
+        data.clear();
+
+        let max = match inner.classes.last() {
+            Some(class) => class.size,
+            None => {
+                inner.discarded.fetch_add(1, Ordering::Relaxed);
+                return
+            }
+        };
+
+        if data.capacity() > max {
+            data.shrink_to(max);
+            inner.shrunk.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let class = match inner.classes.iter().rev().find(|class| class.size <= data.capacity()) {
+            Some(class) => class,
+            None => {
+                inner.discarded.fetch_add(1, Ordering::Relaxed);
+                return
+            }
+        };
+
+        let mut idle = lock(&class.idle);
+
+        if idle.len() >= inner.max_idle {
+            inner.discarded.fetch_add(1, Ordering::Relaxed);
+            return
+        }
+
+        idle.push(data);
+        inner.returned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 缓冲区空闲且容量超过 limit 时换成一个默认大小的，大的还回池里收缩
+    pub fn trim(&self, buffer: &mut Buffer, limit: usize) {
+        if buffer.is_empty() && buffer.capacity() > limit {
+            let small = self.get(0);
+            let large = ::std::mem::replace(buffer, small);
+            self.put(large);
+        }
+    }
+
+    /// 释放所有空闲的缓冲区
+    pub fn clear(&self) {
+        for class in self.inner.classes.iter() {
+            lock(&class.idle).clear();
+        }
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        let inner = &self.inner;
+        let mut idle = 0;
+        let mut idle_bytes = 0;
+
+        for class in inner.classes.iter() {
+            let class = lock(&class.idle);
+            idle += class.len();
+            idle_bytes += class.iter().map(|data| data.capacity()).sum::<usize>();
+        }
+
+        BufferPoolStats {
+            hits: inner.hits.load(Ordering::Relaxed),
+            misses: inner.misses.load(Ordering::Relaxed),
+            returned: inner.returned.load(Ordering::Relaxed),
+            discarded: inner.discarded.load(Ordering::Relaxed),
+            shrunk: inner.shrunk.load(Ordering::Relaxed),
+            idle: idle,
+            idle_bytes: idle_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool() -> BufferPool {
+        BufferPool::with_classes(&[4096, 0, 1024, 4096], 2)
+    }
+
+    #[test]
+    fn get_rounds_up_to_class() {
+        let pool = pool();
+
+        assert_eq!(pool.get(0).capacity(), 1024);
+        assert_eq!(pool.get(1024).capacity(), 1024);
+        assert_eq!(pool.get(1025).capacity(), 4096);
+
+        //比最大等级大的直接分配
+        assert_eq!(pool.get(10000).capacity(), 10000);
+
+        let stats = pool.stats();
+        assert_eq!(stats.hits, 0);
+        assert_eq!(stats.misses, 4);
+    }
+
+    #[test]
+    fn put_then_get_hits() {
+        let pool = pool();
+
+        let mut buffer = pool.get(2000);
+        buffer.extend_from_slice(b"leftover");
+        pool.put(buffer);
+
+        let stats = pool.stats();
+        assert_eq!(stats.returned, 1);
+        assert_eq!(stats.idle, 1);
+        assert_eq!(stats.idle_bytes, 4096);
+
+        //还回时清空
+        let buffer = pool.get(2000);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.capacity(), 4096);
+
+        let stats = pool.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.idle, 0);
+        assert_eq!(stats.idle_bytes, 0);
+    }
+
+    #[test]
+    fn large_buffers_shrink_to_top_class() {
+        let pool = pool();
+
+        pool.put(Buffer::with_capacity(100000));
+
+        let stats = pool.stats();
+        assert_eq!(stats.shrunk, 1);
+        assert_eq!(stats.returned, 1);
+        assert!(stats.idle_bytes >= 4096 && stats.idle_bytes < 100000);
+        assert!(pool.get(4096).capacity() < 100000);
+    }
+
+    #[test]
+    fn discards_small_and_overflow() {
+        let pool = pool();
+
+        pool.put(Buffer::with_capacity(100));
+        assert_eq!(pool.stats().discarded, 1);
+
+        for _ in 0..3 {
+            pool.put(Buffer::with_capacity(1024));
+        }
+
+        let stats = pool.stats();
+        assert_eq!(stats.returned, 2);
+        assert_eq!(stats.discarded, 2);
+        assert_eq!(stats.idle, 2);
+
+        pool.clear();
+        assert_eq!(pool.stats().idle, 0);
+        assert_eq!(pool.stats().idle_bytes, 0);
+    }
+
+    #[test]
+    fn no_classes() {
+        let pool = BufferPool::with_classes(&[0], 2);
+
+        assert_eq!(pool.get(10).capacity(), 10);
+        pool.put(Buffer::with_capacity(10));
+
+        let stats = pool.stats();
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.discarded, 1);
+        assert_eq!(stats.idle, 0);
+    }
+
+    #[test]
+    fn trim_only_idle_large_buffers() {
+        let pool = pool();
+
+        let mut buffer = Buffer::with_capacity(8192);
+        buffer.extend_from_slice(b"busy");
+        pool.trim(&mut buffer, 4096);
+        assert_eq!(buffer.capacity(), 8192);
+
+        buffer.clear();
+        pool.trim(&mut buffer, 4096);
+        assert_eq!(buffer.capacity(), 1024);
+        assert_eq!(pool.stats().shrunk, 1);
+        assert_eq!(pool.stats().idle, 1);
+    }
+}
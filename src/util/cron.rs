@@ -0,0 +1,268 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Local, TimeZone, Datelike, Timelike, NaiveDate, NaiveDateTime};
+use chrono::Duration as OldDuration;
+
+use error::{MioResult, MioError};
+
+//找下一次时间时最多跳这么多步，找不到说明表达式永远不会匹配（比如 2 月 30 日）
+const MAX_STEPS: usize = 100_000;
+
+/// cron 表达式，五个字段：分 时 日 月 星期
+///
+/// 每个字段支持 `*`、数字、范围 `a-b`、步长 `*/n` 和 `a-b/n`，以及用逗号分隔的列表；
+/// 星期 0 和 7 都表示周日。日和星期都不是 `*` 时，任意一个匹配就执行，和常见的 cron 一致。
+/// 也支持 `@yearly`、`@monthly`、`@weekly`、`@daily`、`@hourly`。时间按本地时区计算。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    //日或星期是 * 时按另一个字段匹配
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn parse(expr: &str) -> MioResult<Cron> {
+        let expr = match expr.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            expr => expr,
+        };
+
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+
+        if fields.len() != 5 {
+            return Err(invalid(expr, "expected 5 fields"))
+        }
+
+        let mut weekdays = parse_field(fields[4], 0, 7).map_err(|err| invalid(expr, &err))?;
+
+        //7 也是周日
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+
+        Ok(Cron {
+            minutes: parse_field(fields[0], 0, 59).map_err(|err| invalid(expr, &err))?,
+            hours: parse_field(fields[1], 0, 23).map_err(|err| invalid(expr, &err))?,
+            days: parse_field(fields[2], 1, 31).map_err(|err| invalid(expr, &err))?,
+            months: parse_field(fields[3], 1, 12).map_err(|err| invalid(expr, &err))?,
+            weekdays: weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    /// time 之后（不含）第一个匹配的时间，精确到分钟；永远不会匹配时返回 None
+    pub fn next_after(&self, time: &DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = time.naive_local();
+        let mut next = start.date().and_hms(start.hour(), start.minute(), 0) + OldDuration::minutes(1);
+
+        for _ in 0..MAX_STEPS {
+            if !contains(self.months, next.month()) {
+                let (year, month) = if next.month() == 12 { (next.year() + 1, 1) } else { (next.year(), next.month() + 1) };
+                next = NaiveDate::from_ymd(year, month, 1).and_hms(0, 0, 0);
+                continue;
+            }
+
+            if !self.matches_day(&next) {
+                next = next.date().succ().and_hms(0, 0, 0);
+                continue;
+            }
+
+            if !contains(self.hours, next.hour()) {
+                next = next.date().and_hms(next.hour(), 0, 0) + OldDuration::hours(1);
+                continue;
+            }
+
+            if !contains(self.minutes, next.minute()) {
+                next = next + OldDuration::minutes(1);
+                continue;
+            }
+
+            //夏令时跳过的时间不存在，接着往后找
+            match Local.from_local_datetime(&next).earliest() {
+                Some(time) => return Some(time),
+                None => next = next + OldDuration::minutes(1),
+            }
+        }
+
+        None
+    }
+
+    fn matches_day(&self, time: &NaiveDateTime) -> bool {
+        let day = contains(self.days, time.day());
+        let weekday = contains(self.weekdays, time.weekday().num_days_from_sunday());
+
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+}
+
+impl FromStr for Cron {
+    type Err = MioError;
+
+    fn from_str(expr: &str) -> MioResult<Cron> {
+        Cron::parse(expr)
+    }
+}
+
+fn contains(mask: u64, value: u32) -> bool {
+    mask & (1 << value) != 0
+}
+
+fn invalid(expr: &str, reason: &str) -> MioError {
+    MioError::Error(format!("invalid cron expression \"{}\": {}", expr, reason))
+}
+
+//解析一个字段，返回按位表示的取值集合
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut mask = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(pos) => {
+                let step: u32 = part[pos + 1..].parse().map_err(|_| format!("invalid step in \"{}\"", part))?;
+
+                if step == 0 {
+                    return Err(format!("zero step in \"{}\"", part))
+                }
+
+                (&part[..pos], step)
+            }
+            None => (part, 1),
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else {
+            match range.find('-') {
+                Some(pos) => (parse_value(&range[..pos], min, max)?, parse_value(&range[pos + 1..], min, max)?),
+                //a/n 表示从 a 开始到最大值
+                None if step > 1 => (parse_value(range, min, max)?, max),
+                None => {
+                    let value = parse_value(range, min, max)?;
+                    (value, value)
+                }
+            }
+        };
+
+        if start > end {
+            return Err(format!("invalid range \"{}\"", range))
+        }
+
+        let mut value = start;
+
+        while value <= end {
+            mask |= 1 << value;
+            value += step;
+        }
+    }
+
+    Ok(mask)
+}
+
+fn parse_value(value: &str, min: u32, max: u32) -> Result<u32, String> {
+    match value.parse::<u32>() {
+        Ok(value) if value >= min && value <= max => Ok(value),
+        _ => Err(format!("\"{}\" is not between {} and {}", value, min, max)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local.ymd(year, month, day).and_hms(hour, minute, 0)
+    }
+
+    fn next(expr: &str, from: DateTime<Local>) -> Option<DateTime<Local>> {
+        Cron::parse(expr).unwrap().next_after(&from)
+    }
+
+    fn values(mask: u64) -> Vec<u32> {
+        (0..64).filter(|&value| contains(mask, value)).collect()
+    }
+
+    #[test]
+    fn parse_steps_ranges_and_lists() {
+        let cron = Cron::parse("*/15 1-10/3 5/10 1,2,11-12 *").unwrap();
+
+        assert_eq!(values(cron.minutes), vec![0, 15, 30, 45]);
+        assert_eq!(values(cron.hours), vec![1, 4, 7, 10]);
+        assert_eq!(values(cron.days), vec![5, 15, 25]);
+        assert_eq!(values(cron.months), vec![1, 2, 11, 12]);
+        assert_eq!(values(cron.weekdays), (0..7).collect::<Vec<u32>>());
+        assert!(!cron.any_day);
+        assert!(cron.any_weekday);
+    }
+
+    #[test]
+    fn sunday_is_zero_or_seven() {
+        assert_eq!(Cron::parse("0 0 * * 7").unwrap(), Cron::parse("0 0 * * 0").unwrap());
+        assert_eq!(values(Cron::parse("0 0 * * 5-7").unwrap().weekdays), vec![0, 5, 6]);
+    }
+
+    #[test]
+    fn aliases() {
+        assert_eq!(Cron::parse("@daily").unwrap(), Cron::parse("0 0 * * *").unwrap());
+        assert_eq!(Cron::parse("@weekly").unwrap(), Cron::parse("0 0 * * 0").unwrap());
+        assert_eq!("@hourly".parse::<Cron>().unwrap(), Cron::parse("0 * * * *").unwrap());
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for expr in ["* * * *", "* * * * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "* * * 13 *", "* * * * 8",
+                     "*/0 * * * *", "5-1 * * * *", "a * * * *", "1- * * * *", "*/x * * * *", "@often"].iter() {
+            assert!(Cron::parse(expr).is_err(), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn next_minute_is_exclusive() {
+        assert_eq!(next("* * * * *", Local.ymd(2026, 1, 15).and_hms(10, 30, 45)), Some(at(2026, 1, 15, 10, 31)));
+        assert_eq!(next("*/15 * * * *", at(2026, 1, 15, 10, 31)), Some(at(2026, 1, 15, 10, 45)));
+        assert_eq!(next("*/15 * * * *", at(2026, 1, 15, 10, 45)), Some(at(2026, 1, 15, 11, 0)));
+    }
+
+    #[test]
+    fn next_rolls_over_days_months_and_years() {
+        assert_eq!(next("0 9 * * *", at(2026, 1, 15, 10, 0)), Some(at(2026, 1, 16, 9, 0)));
+        assert_eq!(next("30 8 1 * *", at(2026, 1, 15, 10, 0)), Some(at(2026, 2, 1, 8, 30)));
+        assert_eq!(next("0 0 1 1 *", at(2026, 6, 1, 0, 0)), Some(at(2027, 1, 1, 0, 0)));
+        assert_eq!(next("0 0 29 2 *", at(2026, 3, 1, 0, 0)), Some(at(2028, 2, 29, 0, 0)));
+    }
+
+    #[test]
+    fn day_of_month_or_day_of_week() {
+        //2026-01-01 是周四，13 号或者周五都执行
+        let cron = "0 0 13 * 5";
+
+        assert_eq!(next(cron, at(2026, 1, 1, 0, 0)), Some(at(2026, 1, 2, 0, 0)));
+        assert_eq!(next(cron, at(2026, 1, 2, 0, 0)), Some(at(2026, 1, 9, 0, 0)));
+        assert_eq!(next(cron, at(2026, 1, 9, 0, 0)), Some(at(2026, 1, 13, 0, 0)));
+        assert_eq!(next(cron, at(2026, 1, 13, 0, 0)), Some(at(2026, 1, 16, 0, 0)));
+
+        //只限制其中一个时按那一个匹配
+        assert_eq!(next("0 0 13 * *", at(2026, 1, 1, 0, 0)), Some(at(2026, 1, 13, 0, 0)));
+        assert_eq!(next("0 12 * * 1-5", at(2026, 1, 3, 0, 0)), Some(at(2026, 1, 5, 12, 0)));
+    }
+
+    #[test]
+    fn never_matching_gives_up_after_max_steps() {
+        assert_eq!(next("0 0 30 2 *", at(2026, 1, 1, 0, 0)), None);
+        assert_eq!(next("0 0 31 4,6,9,11 *", at(2026, 1, 1, 0, 0)), None);
+    }
+}
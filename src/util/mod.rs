@@ -1,3 +1,6 @@
-
 pub mod threadpool;
-pub mod url;
\ No newline at end of file
+pub mod buffer_pool;
+pub mod timer;
+pub mod url;
+pub mod sync;
+pub mod cron;
\ No newline at end of file
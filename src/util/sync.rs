@@ -0,0 +1,8 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// 加锁，持有锁的线程 panic 过也照常取出数据
+///
+/// 锁里的状态都是简单的计数和队列，panic 不会留下半截的数据，没必要让后面所有的调用者跟着 panic。
+pub fn lock<'a, T>(mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
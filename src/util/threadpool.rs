@@ -1,12 +1,21 @@
-use std::sync::{Arc, Mutex, Condvar};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Condvar, PoisonError};
+use std::sync::atomic::{AtomicBool, AtomicUsize, AtomicU64, Ordering};
 use std::collections::VecDeque;
-use std::thread;
-use std::time::Duration;
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::cell::Cell;
+use std::cmp;
+use std::thread::{self, JoinHandle};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
 
 use num_cpus;
 
-trait FnBox {
+use super::sync::lock;
+
+pub trait FnBox {
     fn call_box(self: Box<Self>);
 }
 
@@ -18,15 +27,164 @@ impl<F: FnOnce()> FnBox for F {
 
 type Truck<'a> = Box<FnBox + Send + 'a>;
 
+/// 按排队时间削减负载（CoDel）
+///
+/// 任务的排队时间持续超过 target 达到 interval 之后开始拒绝新任务，排队时间降回 target 以下后恢复。
+#[derive(Debug, Clone, Copy)]
+pub struct CoDel {
+    pub target: Duration,
+    pub interval: Duration,
+}
+
+//每个任务都要更新，只用原子变量，没开启时只读一次 target
+struct CoDelState {
+    //纳秒，0 表示没有开启
+    target: AtomicU64,
+    interval: AtomicU64,
+    //排队时间第一次超过 target 后的 interval 截止时间，相对 epoch 的纳秒，0 表示没有超过
+    above_until: AtomicU64,
+    dropping: AtomicBool,
+    epoch: Instant,
+}
+
+/// 任务调度方式，创建线程池时选择
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheduler {
+    /// 所有线程共用一个队列，线程数在 min 和 max 之间按负载增减
+    Shared,
+    /// 每个线程一个本地队列，空闲时从其他线程的队列里偷任务；线程数固定为 max
+    ///
+    /// 工作线程里提交的任务放进自己的队列，其他线程提交的轮流分给各个线程，减少争用同一把锁。
+    /// 任务主要在工作线程里派生子任务时明显更快；都从外部线程提交时不比 Shared 快，
+    /// 线程数也不会增加，任务会阻塞时用 Shared。测试结果见 `pool_bench`。
+    WorkStealing,
+}
+
+/// 任务优先级，线程池先执行高优先级的任务
+///
+/// 只对共用队列的调度方式有效，工作窃取时都按 Normal 处理。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority::Normal
+    }
+}
+
+/// 关闭线程池时怎么处理还在排队的任务
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownPolicy {
+    /// 截止时间之前继续执行排队的任务
+    Drain,
+    /// 直接丢弃排队的任务，只等待正在执行的
+    Discard,
+}
+
+/// 线程池关闭的结果
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ShutdownReport {
+    /// 没有执行就被丢弃的任务数
+    pub abandoned: usize,
+    /// 已经退出并 join 的线程数
+    pub joined: usize,
+    /// 截止时间到了还在执行任务、没有 join 的线程数
+    pub detached: usize,
+}
+
 pub struct Pool {
     inner: Arc<Inner>,
 }
 
+type Queue = VecDeque<(Instant, Truck<'static>)>;
+
+//按优先级分开排队，记录每个优先级正在执行的任务数和预留的线程数
+struct Classes {
+    queues: [Queue; 3],
+    running: [usize; 3],
+    reserved: [usize; 3],
+}
+
+impl Classes {
+    fn new(capacity: usize) -> Classes {
+        Classes {
+            queues: [VecDeque::with_capacity(capacity), VecDeque::new(), VecDeque::new()],
+            running: [0; 3],
+            reserved: [0; 3],
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.is_empty())
+    }
+
+    fn push(&mut self, priority: Priority, task: (Instant, Truck<'static>)) {
+        self.queues[priority as usize].push_back(task);
+    }
+
+    //按优先级取任务；reserve 为 true 时给更高优先级预留的线程不执行低优先级的任务
+    fn pop(&mut self, workers: usize, reserve: bool) -> Option<(Priority, (Instant, Truck<'static>))> {
+        let mut reserved = 0;
+
+        for &priority in [Priority::High, Priority::Normal, Priority::Low].iter() {
+            let class = priority as usize;
+
+            if !self.queues[class].is_empty() {
+                //这个优先级和更低的优先级一共能用 workers - reserved 个线程
+                let busy: usize = self.running[class..].iter().sum();
+
+                if !reserve || busy + reserved < workers {
+                    self.running[class] += 1;
+                    return self.queues[class].pop_front().map(|task| (priority, task))
+                }
+            }
+
+            reserved += self.reserved[class];
+        }
+
+        None
+    }
+
+    fn finish(&mut self, priority: Priority) {
+        self.running[priority as usize] -= 1;
+    }
+
+    fn drain(&mut self) -> usize {
+        self.queues.iter_mut().map(|queue| queue.drain(..).count()).sum()
+    }
+}
+
 struct Inner {
-    queue: Mutex<VecDeque<Truck<'static>>>,
+    //工作窃取时只用来让空闲线程等待
+    queue: Mutex<Classes>,
     condvar: Condvar,
+    scheduler: Scheduler,
+    //工作窃取时每个线程的本地队列
+    locals: Vec<Mutex<Queue>>,
+    //工作窃取时所有本地队列里的任务数
+    pending: AtomicUsize,
+    //已经取消但还留在队列里的任务数，不计入排队的任务
+    cancelled: AtomicUsize,
+    next_local: AtomicUsize,
     active: AtomicUsize,
     waiting: AtomicUsize,
+    //0 表示不限制
+    max_queue: AtomicUsize,
+    codel: CoDelState,
+    rejected: AtomicUsize,
+    panicked: AtomicUsize,
+    shutdown: AtomicBool,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    name: Option<String>,
+    next_id: AtomicUsize,
     min_num: usize,
     max_num: usize,
 }
@@ -37,7 +195,7 @@ struct Count<'a> {
 
 impl<'a> Count<'a> {
     fn add(num: &'a AtomicUsize) -> Count<'a> {
-        num.fetch_add(1, Ordering::Release);
+        num.fetch_add(1, Ordering::SeqCst);
         
         Count {
             num: num,
@@ -47,7 +205,307 @@ impl<'a> Count<'a> {
 
 impl<'a> Drop for Count<'a> {
     fn drop(&mut self) {
-        self.num.fetch_sub(1, Ordering::Release);
+        self.num.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// 线程池状态的只读句柄，可以在任意线程查看
+#[derive(Clone)]
+pub struct PoolMonitor {
+    inner: Arc<Inner>,
+}
+
+impl PoolMonitor {
+    /// 排队等待执行的任务数
+    pub fn queued(&self) -> usize {
+        queued(&self.inner)
+    }
+
+    /// 存活的线程数，包括空闲等待的
+    pub fn active(&self) -> usize {
+        self.inner.active.load(Ordering::Acquire)
+    }
+
+    /// 空闲等待任务的线程数
+    pub fn waiting(&self) -> usize {
+        self.inner.waiting.load(Ordering::Acquire)
+    }
+
+    /// 累计被拒绝的任务数
+    pub fn rejected(&self) -> usize {
+        self.inner.rejected.load(Ordering::Relaxed)
+    }
+
+    /// 累计 panic 的任务数
+    pub fn panicked(&self) -> usize {
+        self.inner.panicked.load(Ordering::Relaxed)
+    }
+}
+
+/// 提交任务的句柄，可以发到其他线程使用，不影响线程池的生命周期
+#[derive(Clone)]
+pub struct PoolHandle {
+    inner: Arc<Inner>,
+}
+
+impl PoolHandle {
+    pub fn execute<F>(&self, handle: F)
+        where F: FnOnce() + Send + 'static
+    {
+        execute(&self.inner, Priority::Normal, Box::new(handle));
+    }
+
+    pub fn execute_with<F>(&self, priority: Priority, handle: F)
+        where F: FnOnce() + Send + 'static
+    {
+        execute(&self.inner, priority, Box::new(handle));
+    }
+
+    /// 提交任务，通过返回的句柄取结果
+    ///
+    /// 在线程池的任务里用 `join_timeout` 等待时，线程全都在等待排队的子任务就只能等到超时。
+    pub fn spawn<F, T>(&self, task: F) -> TaskHandle<T>
+        where F: FnOnce() -> T + Send + 'static, T: Send + 'static
+    {
+        spawn(&self.inner, task)
+    }
+}
+
+/// 任务没有正常返回的原因
+#[derive(Debug)]
+pub enum TaskError {
+    /// 开始执行之前被取消
+    Cancelled,
+    /// 线程池已经关闭，任务没有执行
+    Abandoned,
+    /// 任务 panic，带着 panic 的参数，可以用 `panic::resume_unwind` 继续抛出
+    Panicked(Box<dyn Any + Send>),
+    /// 结果已经被 `try_join` 或 `join_timeout` 取走
+    Taken,
+}
+
+impl fmt::Display for TaskError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TaskError::Cancelled => write!(f, "task cancelled"),
+            TaskError::Abandoned => write!(f, "task abandoned by pool shutdown"),
+            TaskError::Panicked(_) => write!(f, "task panicked"),
+            TaskError::Taken => write!(f, "task result already taken"),
+        }
+    }
+}
+
+impl Error for TaskError {}
+
+enum State<T> {
+    Pending(Box<dyn FnOnce() -> T + Send>),
+    Running,
+    Done(thread::Result<T>),
+    Cancelled,
+    Abandoned,
+    Taken,
+}
+
+struct Task<T> {
+    state: Mutex<State<T>>,
+    condvar: Condvar,
+    //cancel 成功过，队列里的任务丢弃时从线程池的取消计数里减掉
+    cancelled: AtomicBool,
+}
+
+impl<T> Task<T> {
+    //还没开始执行时在当前线程执行，返回是否 panic
+    fn run(&self) -> bool {
+        let task = {
+            let mut state = lock(&self.state);
+
+            match mem::replace(&mut *state, State::Running) {
+                State::Pending(task) => task,
+                other => {
+                    *state = other;
+                    return false
+                }
+            }
+        };
+
+        let result = panic::catch_unwind(AssertUnwindSafe(task));
+        let panicked = result.is_err();
+
+        *lock(&self.state) = State::Done(result);
+        self.condvar.notify_all();
+
+        panicked
+    }
+}
+
+//跟着任务进入队列，执行完或者被丢弃时只释放一次
+//没有执行就被丢弃时（线程池关闭）通知句柄，已经取消的任务离开队列后不再计入取消数
+struct Slot<T> {
+    task: Arc<Task<T>>,
+    pool: Arc<Inner>,
+}
+
+impl<T> Drop for Slot<T> {
+    fn drop(&mut self) {
+        if self.task.cancelled.load(Ordering::Acquire) {
+            self.pool.cancelled.fetch_sub(1, Ordering::SeqCst);
+        }
+
+        let mut state = lock(&self.task.state);
+
+        if let State::Pending(_) = *state {
+            *state = State::Abandoned;
+            self.task.condvar.notify_all();
+        }
+    }
+}
+
+/// `spawn` 返回的任务句柄
+///
+/// 结果只能取一次，`try_join` 或 `join_timeout` 取到结果之后再取返回 `TaskError::Taken`。丢弃句柄不会取消任务。
+pub struct TaskHandle<T> {
+    task: Arc<Task<T>>,
+    pool: Arc<Inner>,
+}
+
+impl<T> TaskHandle<T> {
+    /// 等待任务结束，任务还在排队时直接在当前线程执行
+    ///
+    /// 在线程池的任务里等待子任务时，不会因为所有线程都在等待排在后面的子任务而卡住。
+    pub fn join(self) -> Result<T, TaskError> {
+        self.task.run();
+
+        let mut state = lock(&self.task.state);
+
+        loop {
+            if let Some(result) = take(&mut state) {
+                return result
+            }
+
+            state = self.task.condvar.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// 任务还没结束时返回 None
+    pub fn try_join(&mut self) -> Option<Result<T, TaskError>> {
+        take(&mut lock(&self.task.state))
+    }
+
+    /// 最多等待 timeout，超时返回 None，之后可以继续等
+    pub fn join_timeout(&mut self, timeout: Duration) -> Option<Result<T, TaskError>> {
+        let deadline = Instant::now() + timeout;
+        let mut state = lock(&self.task.state);
+
+        loop {
+            if let Some(result) = take(&mut state) {
+                return Some(result)
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                return None
+            }
+
+            state = self.task.condvar.wait_timeout(state, deadline - now).unwrap_or_else(PoisonError::into_inner).0;
+        }
+    }
+
+    /// 任务还在排队时取消，已经开始执行的不能取消
+    ///
+    /// 取消的任务留在队列里，取出时直接跳过，不再计入排队的任务数。
+    pub fn cancel(&self) -> bool {
+        let mut state = lock(&self.task.state);
+
+        match *state {
+            State::Pending(_) => {
+                *state = State::Cancelled;
+                self.pool.cancelled.fetch_add(1, Ordering::SeqCst);
+                self.task.cancelled.store(true, Ordering::Release);
+                self.task.condvar.notify_all();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        match *lock(&self.task.state) {
+            State::Pending(_) | State::Running => false,
+            _ => true,
+        }
+    }
+}
+
+//取出结果，任务还没结束时返回 None
+fn take<T>(state: &mut State<T>) -> Option<Result<T, TaskError>> {
+    match *state {
+        State::Pending(_) | State::Running => return None,
+        State::Taken => return Some(Err(TaskError::Taken)),
+        _ => {}
+    }
+
+    match mem::replace(state, State::Taken) {
+        State::Done(Ok(value)) => Some(Ok(value)),
+        State::Done(Err(err)) => Some(Err(TaskError::Panicked(err))),
+        State::Cancelled => Some(Err(TaskError::Cancelled)),
+        State::Abandoned => Some(Err(TaskError::Abandoned)),
+        _ => unreachable!(),
+    }
+}
+
+impl CoDelState {
+    fn new() -> CoDelState {
+        CoDelState {
+            target: AtomicU64::new(0),
+            interval: AtomicU64::new(0),
+            above_until: AtomicU64::new(0),
+            dropping: AtomicBool::new(false),
+            epoch: Instant::now(),
+        }
+    }
+
+    fn configure(&self, config: Option<CoDel>) {
+        let (target, interval) = config.map_or((0, 0), |config| ((config.target.as_nanos() as u64).max(1), config.interval.as_nanos() as u64));
+
+        self.interval.store(interval, Ordering::Relaxed);
+        self.above_until.store(0, Ordering::Relaxed);
+        self.dropping.store(false, Ordering::Relaxed);
+        self.target.store(target, Ordering::Release);
+    }
+
+    //工作线程取出任务时根据排队时间更新状态，状态没变时不写，避免工作线程之间争抢缓存行
+    fn observe(&self, queued_at: Instant) {
+        let target = self.target.load(Ordering::Acquire);
+
+        if target == 0 {
+            return
+        }
+
+        let now = Instant::now();
+
+        if ((now - queued_at).as_nanos() as u64) < target {
+            if self.above_until.load(Ordering::Relaxed) != 0 {
+                self.above_until.store(0, Ordering::Relaxed);
+            }
+
+            if self.dropping.load(Ordering::Relaxed) {
+                self.dropping.store(false, Ordering::Relaxed);
+            }
+
+            return
+        }
+
+        //加 1 保证不是 0
+        let now = (now - self.epoch).as_nanos() as u64 + 1;
+        let until = self.above_until.load(Ordering::Relaxed);
+
+        if until == 0 {
+            let until = now + self.interval.load(Ordering::Relaxed);
+            let _ = self.above_until.compare_exchange(0, until, Ordering::Relaxed, Ordering::Relaxed);
+        } else if now >= until && !self.dropping.load(Ordering::Relaxed) {
+            self.dropping.store(true, Ordering::Relaxed);
+        }
     }
 }
 
@@ -56,101 +514,764 @@ impl Pool {
         let min_num = num_cpus::get();
         let max_num = min_num * 16;
 
-        let pool = Pool {
-            inner: Arc::new(Inner {
-                queue: Mutex::new(VecDeque::with_capacity(max_num * 16)),
-                condvar: Condvar::new(),
-                active: AtomicUsize::new(0),
-                waiting: AtomicUsize::new(0),
-                min_num: min_num,
-                max_num: max_num,
-            }),
-        };
+        Pool::build(Scheduler::Shared, None, min_num, max_num, max_num * 16)
+    }
 
-        for _ in 0..min_num {
-            pool.thread();
-        }
+    pub fn with_capacity(min: usize, max: usize) -> Pool {
+        Pool::build(Scheduler::Shared, None, min, max, max)
+    }
 
-        pool
+    /// 工作线程命名为 `name-序号`，方便在调试器和日志里区分
+    pub fn with_name(name: &str, min: usize, max: usize) -> Pool {
+        Pool::build(Scheduler::Shared, Some(name.to_owned()), min, max, max)
     }
 
-    pub fn with_capacity(min: usize, max: usize) -> Pool {
+    /// 指定调度方式，name 为 None 时线程不命名
+    pub fn with_scheduler(scheduler: Scheduler, name: Option<&str>, min: usize, max: usize) -> Pool {
+        Pool::build(scheduler, name.map(|name| name.to_owned()), min, max, max)
+    }
+
+    fn build(scheduler: Scheduler, name: Option<String>, min: usize, max: usize, capacity: usize) -> Pool {
+        let locals = match scheduler {
+            Scheduler::Shared => 0,
+            Scheduler::WorkStealing => cmp::max(max, 1),
+        };
+
         let pool = Pool {
             inner: Arc::new(Inner {
-                queue: Mutex::new(VecDeque::with_capacity(max)),
+                queue: Mutex::new(Classes::new(capacity)),
                 condvar: Condvar::new(),
+                scheduler: scheduler,
+                locals: (0..locals).map(|_| Mutex::new(VecDeque::new())).collect(),
+                pending: AtomicUsize::new(0),
+                cancelled: AtomicUsize::new(0),
+                next_local: AtomicUsize::new(0),
                 active: AtomicUsize::new(0),
                 waiting: AtomicUsize::new(0),
+                max_queue: AtomicUsize::new(0),
+                codel: CoDelState::new(),
+                rejected: AtomicUsize::new(0),
+                panicked: AtomicUsize::new(0),
+                shutdown: AtomicBool::new(false),
+                threads: Mutex::new(Vec::new()),
+                name: name,
+                next_id: AtomicUsize::new(0),
                 min_num: min,
                 max_num: max,
             })
         };
 
-        for _ in 0..min {
-            pool.thread();
+        match scheduler {
+            Scheduler::Shared => {
+                for _ in 0..min {
+                    spawn_thread(&pool.inner, None);
+                }
+            }
+            Scheduler::WorkStealing => {
+                for index in 0..locals {
+                    spawn_thread(&pool.inner, Some(index));
+                }
+            }
         }
 
         pool
     }
-   
+
+    pub fn scheduler(&self) -> Scheduler {
+        self.inner.scheduler
+    }
+
+    pub fn monitor(&self) -> PoolMonitor {
+        PoolMonitor {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// 提交任务的句柄，可以发到其他线程使用
+    pub fn handle(&self) -> PoolHandle {
+        PoolHandle {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// 排队任务数上限，None 表示不限制
+    pub fn set_max_queue(&self, max: Option<usize>) {
+        self.inner.max_queue.store(max.unwrap_or(0), Ordering::Release);
+    }
+
+    /// 按排队时间拒绝任务，None 表示关闭
+    pub fn set_codel(&self, codel: Option<CoDel>) {
+        self.inner.codel.configure(codel);
+    }
+
+    /// 给 priority 和更高的优先级预留 workers 个线程，更低优先级的任务不会占用这些线程
+    ///
+    /// 按线程数上限计算，只对共用队列的调度方式有效。
+    pub fn reserve(&self, priority: Priority, workers: usize) {
+        lock(&self.inner.queue).reserved[priority as usize] = workers;
+    }
+
+    /// 队列已满或排队时间过长，新任务会被拒绝
+    pub fn is_overloaded(&self) -> bool {
+        let queued = queued(&self.inner);
+
+        //队列空了说明已经追上，不再拒绝
+        if queued == 0 {
+            return false
+        }
+
+        let max = self.inner.max_queue.load(Ordering::Acquire);
+
+        if max > 0 && queued >= max {
+            return true
+        }
+
+        self.inner.codel.dropping.load(Ordering::Relaxed)
+    }
+
+    /// 高优先级的任务不会因为过载被拒绝
+    pub fn is_overloaded_for(&self, priority: Priority) -> bool {
+        priority != Priority::High && self.is_overloaded()
+    }
+
+    /// 过载或已经关闭时不执行，把任务原样返回
+    pub fn try_execute<F>(&self, handle: F) -> Result<(), F>
+        where F: FnOnce() + Send + 'static
+    {
+        if self.is_shutdown() || self.is_overloaded() {
+            self.reject();
+            return Err(handle)
+        }
+
+        self.execute(handle);
+        Ok(())
+    }
+
+    /// 记录一次拒绝，调用方自己判断过载时使用
+    pub fn reject(&self) {
+        self.inner.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 已经关闭时任务直接丢弃，记为拒绝
     pub fn execute<F>(&self, handle: F)
         where F: FnOnce() + Send + 'static
     {
-        if self.inner.waiting.load(Ordering::Acquire) == 0 && self.inner.active.load(Ordering::Acquire) < self.inner.max_num + 1 {
-            self.thread();
+        execute(&self.inner, Priority::Normal, Box::new(handle));
+    }
+
+    pub fn execute_with<F>(&self, priority: Priority, handle: F)
+        where F: FnOnce() + Send + 'static
+    {
+        execute(&self.inner, priority, Box::new(handle));
+    }
+
+    /// 提交任务，通过返回的句柄取结果
+    pub fn spawn<F, T>(&self, task: F) -> TaskHandle<T>
+        where F: FnOnce() -> T + Send + 'static, T: Send + 'static
+    {
+        spawn(&self.inner, task)
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.shutdown.load(Ordering::Acquire)
+    }
+
+    /// 关闭线程池，不再接受新任务，等待工作线程退出直到 deadline
+    ///
+    /// Drain 时排队的任务在截止时间之前继续执行，到期后剩下的丢弃；
+    /// 到期时还在执行任务的线程不再等待。不要在线程池的任务里调用，否则会一直等到截止时间。
+    pub fn shutdown(&self, policy: ShutdownPolicy, deadline: Instant) -> ShutdownReport {
+        let mut report = ShutdownReport::default();
+
+        {
+            let mut queue = lock(&self.inner.queue);
+            self.inner.shutdown.store(true, Ordering::Release);
+
+            if policy == ShutdownPolicy::Discard {
+                report.abandoned += drain(&self.inner, &mut queue);
+            }
+
+            self.inner.condvar.notify_all();
         }
 
-        let mut queue = self.inner.queue.lock().unwrap();
-            
-        queue.push_back(Box::new(handle));
-        self.inner.condvar.notify_one();
+        let threads = mem::replace(&mut *lock(&self.inner.threads), Vec::new());
+
+        //标准库的 join 不能设置超时，只能轮询线程是否结束
+        while Instant::now() < deadline && threads.iter().any(|thread| !thread.is_finished()) {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        report.abandoned += drain(&self.inner, &mut lock(&self.inner.queue));
+
+        for thread in threads {
+            if thread.is_finished() {
+                let _ = thread.join();
+                report.joined += 1;
+            } else {
+                report.detached += 1;
+            }
+        }
+
+        report
+    }
+}
+
+fn execute(inner: &Arc<Inner>, priority: Priority, handle: Truck<'static>) {
+    if inner.shutdown.load(Ordering::Acquire) {
+        inner.rejected.fetch_add(1, Ordering::Relaxed);
+        return
+    }
+
+    if let Scheduler::WorkStealing = inner.scheduler {
+        return push_local(inner, handle)
+    }
+
+    if inner.waiting.load(Ordering::Acquire) == 0 && inner.active.load(Ordering::Acquire) < inner.max_num + 1 {
+        spawn_thread(inner, None);
+    }
+
+    let mut queue = lock(&inner.queue);
+
+    queue.push(priority, (Instant::now(), handle));
+    inner.condvar.notify_one();
+}
+
+thread_local! {
+    //当前工作线程所属的线程池和本地队列序号
+    static LOCAL: Cell<Option<(usize, usize)>> = Cell::new(None);
+}
+
+fn pool_id(inner: &Inner) -> usize {
+    inner as *const Inner as usize
+}
+
+//工作线程里提交的放进自己的队列，其他线程提交的轮流分配
+fn push_local(inner: &Inner, handle: Truck<'static>) {
+    let id = pool_id(inner);
+
+    let index = match LOCAL.with(|local| local.get()) {
+        Some((pool, index)) if pool == id => index,
+        _ => inner.next_local.fetch_add(1, Ordering::Relaxed) % inner.locals.len(),
+    };
+
+    //先计数再入队，其他线程取走任务后减计数时不会减到 0 以下
+    //和工作线程等待前的检查配合：要么它看到 pending 不为 0，要么这里看到它在等待并唤醒
+    inner.pending.fetch_add(1, Ordering::SeqCst);
+
+    lock(&inner.locals[index]).push_back((Instant::now(), handle));
+
+    if inner.waiting.load(Ordering::SeqCst) > 0 {
+        let _queue = lock(&inner.queue);
+        inner.condvar.notify_one();
     }
+}
 
-    fn thread(&self) {
-        let inner = self.inner.clone();
+//先取自己队列里的，没有再从后面的线程开始偷
+fn pop_local(inner: &Inner, index: usize) -> Option<(Instant, Truck<'static>)> {
+    let count = inner.locals.len();
 
-        thread::spawn(move || {
+    for i in 0..count {
+        //都空了就不用再挨个加锁
+        if inner.pending.load(Ordering::SeqCst) == 0 {
+            return None
+        }
+
+        let task = lock(&inner.locals[(index + i) % count]).pop_front();
+
+        if task.is_some() {
+            inner.pending.fetch_sub(1, Ordering::SeqCst);
+            return task
+        }
+    }
 
-            let _active = Count::add(&inner.active);
+    None
+}
+
+//不包括已经取消的任务，取消的任务刚取出还没释放时可能多减，所以不能减到 0 以下
+fn queued(inner: &Inner) -> usize {
+    let queued = match inner.scheduler {
+        Scheduler::Shared => lock(&inner.queue).len(),
+        Scheduler::WorkStealing => inner.pending.load(Ordering::SeqCst),
+    };
+
+    queued.saturating_sub(inner.cancelled.load(Ordering::SeqCst))
+}
+
+//丢弃排队的任务，返回丢弃的数量
+fn drain(inner: &Inner, queue: &mut Classes) -> usize {
+    let mut count = queue.drain();
+
+    for local in inner.locals.iter() {
+        let drained = lock(local).drain(..).count();
+        inner.pending.fetch_sub(drained, Ordering::SeqCst);
+        count += drained;
+    }
+
+    count
+}
+
+fn spawn<F, T>(inner: &Arc<Inner>, task: F) -> TaskHandle<T>
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static
+{
+    let slot = Slot {
+        task: Arc::new(Task {
+            state: Mutex::new(State::Pending(Box::new(task))),
+            condvar: Condvar::new(),
+            cancelled: AtomicBool::new(false),
+        }),
+        pool: inner.clone(),
+    };
+
+    let handle = TaskHandle {
+        task: slot.task.clone(),
+        pool: inner.clone(),
+    };
+
+    execute(inner, Priority::Normal, Box::new(move || {
+        if slot.task.run() {
+            slot.pool.panicked.fetch_add(1, Ordering::Relaxed);
+        }
+    }));
+
+    handle
+}
+
+fn spawn_thread(pool: &Arc<Inner>, local: Option<usize>) {
+    let inner = pool.clone();
+
+    let mut builder = thread::Builder::new();
+
+    if let Some(ref name) = pool.name {
+        builder = builder.name(format!("{}-{}", name, pool.next_id.fetch_add(1, Ordering::Relaxed)));
+    }
+
+    let thread = builder.spawn(move || {
+        let _active = Count::add(&inner.active);
+
+        match local {
+            Some(index) => steal_worker(&inner, index),
+            None => shared_worker(&inner),
+        }
+    });
+
+    //创建线程失败时任务留在队列里，等其他线程执行
+    let thread = match thread {
+        Ok(thread) => thread,
+        Err(_) => return,
+    };
+
+    //空闲退出的线程顺便清理掉
+    let mut threads = lock(&pool.threads);
+    threads.retain(|thread| !thread.is_finished());
+    threads.push(thread);
+}
+
+fn shared_worker(inner: &Inner) {
+    //上一个任务的优先级，下次加锁时再从正在执行的数量里减掉
+    let mut finished = None;
+
+    loop {
+        let (priority, handle) = {
+            let mut queue = lock(&inner.queue);
+
+            if let Some(priority) = finished.take() {
+                queue.finish(priority);
+            }
+
+            let handle;
 
             loop {
-                let handle = {
-                    let mut queue = inner.queue.lock().unwrap();
+                //关闭后不再预留线程，把队列里的任务都执行完
+                let shutdown = inner.shutdown.load(Ordering::Acquire);
 
-                    let handle;
+                if let Some(front) = queue.pop(inner.max_num, !shutdown) {
+                    handle = front;
+                    break;
+                }
 
-                    loop {
-                        if let Some(front) = queue.pop_front() {
-                            handle = front;
-                            break;
-                        }
+                //关闭后把队列里的任务执行完再退出
+                if shutdown {
+                    return;
+                }
 
-                        let _waiting = Count::add(&inner.waiting);
+                let _waiting = Count::add(&inner.waiting);
 
-                        if inner.active.load(Ordering::Acquire) <= inner.min_num {
-                            queue = inner.condvar.wait(queue).unwrap();
-                        } else {
-                            let (q, wait) = inner.condvar.wait_timeout(queue, Duration::from_secs(60)).unwrap();
-                            queue = q;
+                if inner.active.load(Ordering::Acquire) <= inner.min_num {
+                    queue = inner.condvar.wait(queue).unwrap_or_else(PoisonError::into_inner);
+                } else {
+                    let (q, wait) = inner.condvar.wait_timeout(queue, Duration::from_secs(60)).unwrap_or_else(PoisonError::into_inner);
+                    queue = q;
 
-                            if wait.timed_out() && queue.is_empty() && inner.active.load(Ordering::Acquire) > inner.min_num {
-                                return;
-                            }
-                        }
+                    if wait.timed_out() && queue.is_empty() && inner.active.load(Ordering::Acquire) > inner.min_num {
+                        return;
                     }
+                }
+            }
 
-                    handle
-                };
+            handle
+        };
 
-                handle.call_box();
-            }
-        });
+        run(inner, handle);
+        finished = Some(priority);
     }
 }
 
+fn steal_worker(inner: &Inner, index: usize) {
+    LOCAL.with(|local| local.set(Some((pool_id(inner), index))));
+
+    loop {
+        if let Some(handle) = pop_local(inner, index) {
+            run(inner, handle);
+            continue;
+        }
+
+        let queue = lock(&inner.queue);
+        let _waiting = Count::add(&inner.waiting);
+
+        if inner.pending.load(Ordering::SeqCst) > 0 {
+            continue;
+        }
+
+        //关闭后把队列里的任务执行完再退出
+        if inner.shutdown.load(Ordering::Acquire) {
+            return;
+        }
+
+        drop(inner.condvar.wait(queue).unwrap_or_else(PoisonError::into_inner));
+    }
+}
+
+fn run(inner: &Inner, (queued_at, handle): (Instant, Truck<'static>)) {
+    inner.codel.observe(queued_at);
+
+    //任务 panic 不影响工作线程，接着取下一个任务
+    if panic::catch_unwind(AssertUnwindSafe(|| handle.call_box())).is_err() {
+        inner.panicked.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+//不等待，工作线程执行完排队的任务后自己退出
 impl Drop for Pool {
     fn drop(&mut self) {
-        self.inner.active.store(usize::max_value(), Ordering::Release);
+        let _queue = lock(&self.inner.queue);
+        self.inner.shutdown.store(true, Ordering::Release);
         self.inner.condvar.notify_all();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    //只有一个线程并且预留给 High，Normal 和 Low 的任务留在队列里，关闭之前不会执行
+    fn held() -> Pool {
+        let pool = Pool::with_capacity(1, 1);
+        pool.reserve(Priority::High, 1);
+        pool
+    }
+
+    fn deadline() -> Instant {
+        Instant::now() + Duration::from_secs(5)
+    }
+
+    fn counter(pool: &Pool, count: usize) -> Arc<AtomicUsize> {
+        let ran = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..count {
+            let ran = ran.clone();
+            pool.execute(move || { ran.fetch_add(1, Ordering::SeqCst); });
+        }
+
+        ran
+    }
+
+    #[test]
+    fn max_queue_rejects_until_drained() {
+        let pool = held();
+        let ran = counter(&pool, 2);
+
+        assert_eq!(pool.monitor().queued(), 2);
+        assert!(!pool.is_overloaded());
+
+        pool.set_max_queue(Some(2));
+        assert!(pool.is_overloaded());
+        assert!(!pool.is_overloaded_for(Priority::High));
+        assert!(pool.try_execute(|| {}).is_err());
+        assert_eq!(pool.monitor().rejected(), 1);
+
+        pool.set_max_queue(None);
+        assert!(!pool.is_overloaded());
+
+        let report = pool.shutdown(ShutdownPolicy::Drain, deadline());
+        assert_eq!(report.abandoned, 0);
+        assert_eq!(report.detached, 0);
+        assert_eq!(ran.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.monitor().queued(), 0);
+    }
+
+    #[test]
+    fn codel_drops_after_interval_and_recovers() {
+        let state = CoDelState::new();
+        let old = Instant::now() - Duration::from_millis(100);
+
+        //没有开启时不记录
+        state.observe(old);
+        assert_eq!(state.above_until.load(Ordering::Relaxed), 0);
+
+        state.configure(Some(CoDel { target: Duration::from_millis(5), interval: Duration::from_millis(20) }));
+        state.observe(old);
+        assert!(!state.dropping.load(Ordering::Relaxed));
+
+        thread::sleep(Duration::from_millis(30));
+        state.observe(old);
+        assert!(state.dropping.load(Ordering::Relaxed));
+
+        state.observe(Instant::now());
+        assert!(!state.dropping.load(Ordering::Relaxed));
+        assert_eq!(state.above_until.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn codel_overload_needs_queued_tasks() {
+        let pool = held();
+        pool.set_codel(Some(CoDel { target: Duration::from_millis(5), interval: Duration::from_millis(0) }));
+
+        let old = Instant::now() - Duration::from_millis(100);
+        pool.inner.codel.observe(old);
+        pool.inner.codel.observe(old);
+        assert!(pool.inner.codel.dropping.load(Ordering::Relaxed));
+
+        //队列是空的，不拒绝
+        assert!(!pool.is_overloaded());
+
+        let _ran = counter(&pool, 1);
+        assert!(pool.is_overloaded());
+        assert!(!pool.is_overloaded_for(Priority::High));
+        assert!(pool.try_execute(|| {}).is_err());
+
+        pool.set_codel(None);
+        assert!(!pool.is_overloaded());
+        assert!(pool.try_execute(|| {}).is_ok());
+    }
+
+    #[test]
+    fn shutdown_discard_reports_abandoned() {
+        let pool = held();
+        let ran = counter(&pool, 3);
+
+        let report = pool.shutdown(ShutdownPolicy::Discard, deadline());
+        assert_eq!(report.abandoned, 3);
+        assert_eq!(report.detached, 0);
+        assert!(report.joined >= 1);
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+
+        //关闭后提交的任务记为拒绝
+        pool.execute(|| {});
+        assert_eq!(pool.monitor().rejected(), 1);
+    }
+
+    #[test]
+    fn shutdown_detaches_busy_threads_at_deadline() {
+        let pool = Pool::with_capacity(1, 1);
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+
+        started_rx.recv().unwrap();
+
+        let report = pool.shutdown(ShutdownPolicy::Drain, Instant::now() + Duration::from_millis(20));
+        assert_eq!(report.detached, 1);
+        assert_eq!(report.abandoned, 0);
+
+        drop(release_tx);
+    }
+
+    #[test]
+    fn spawn_and_join() {
+        let pool = Pool::with_capacity(1, 2);
+
+        assert_eq!(pool.spawn(|| 1 + 1).join().unwrap(), 2);
+
+        let mut handle = pool.spawn(|| "done");
+
+        match handle.join_timeout(Duration::from_secs(5)) {
+            Some(Ok("done")) => {}
+            _ => panic!("task did not finish"),
+        }
+
+        assert!(handle.is_finished());
+
+        match handle.try_join() {
+            Some(Err(TaskError::Taken)) => {}
+            _ => panic!("result taken twice"),
+        }
+
+        match handle.join() {
+            Err(TaskError::Taken) => {}
+            _ => panic!("result taken twice"),
+        }
+    }
+
+    #[test]
+    fn join_runs_queued_task_inline() {
+        let pool = held();
+        let current = thread::current().id();
+
+        assert_eq!(pool.spawn(move || thread::current().id() == current).join().unwrap(), true);
+    }
+
+    #[test]
+    fn panicked_task_reports_payload() {
+        let pool = Pool::with_capacity(1, 1);
+
+        //join_timeout 不在当前线程执行，panic 由工作线程计数
+        let mut handle = pool.spawn(|| -> () { panic!("boom") });
+
+        match handle.join_timeout(Duration::from_secs(5)) {
+            Some(Err(TaskError::Panicked(payload))) => assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom")),
+            _ => panic!("expected a panic"),
+        }
+
+        //结果先于计数可见，等一下工作线程
+        let deadline = deadline();
+
+        while pool.monitor().panicked() == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(pool.monitor().panicked(), 1);
+        assert_eq!(pool.spawn(|| 3).join().unwrap(), 3);
+    }
+
+    #[test]
+    fn cancelled_task_leaves_queue_count() {
+        let pool = held();
+        pool.set_max_queue(Some(1));
+
+        let handle = pool.spawn(|| 1);
+        assert!(pool.is_overloaded());
+
+        assert!(handle.cancel());
+        assert!(!handle.cancel());
+        assert!(handle.is_finished());
+        assert_eq!(pool.monitor().queued(), 0);
+        assert!(!pool.is_overloaded());
+
+        match handle.join() {
+            Err(TaskError::Cancelled) => {}
+            _ => panic!("expected cancelled"),
+        }
+
+        //取消的任务离开队列后不再计入取消数
+        pool.shutdown(ShutdownPolicy::Discard, deadline());
+        assert_eq!(pool.inner.cancelled.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn discarded_task_is_abandoned() {
+        let pool = held();
+        let handle = pool.spawn(|| 1);
+
+        assert_eq!(pool.shutdown(ShutdownPolicy::Discard, deadline()).abandoned, 1);
+
+        match handle.join() {
+            Err(TaskError::Abandoned) => {}
+            _ => panic!("expected abandoned"),
+        }
+    }
+
+    #[test]
+    fn work_stealing_runs_everything() {
+        let pool = Pool::with_scheduler(Scheduler::WorkStealing, None, 2, 2);
+        assert_eq!(pool.scheduler(), Scheduler::WorkStealing);
+
+        let ran = counter(&pool, 1000);
+
+        let report = pool.shutdown(ShutdownPolicy::Drain, deadline());
+        assert_eq!(report.abandoned, 0);
+        assert_eq!(report.joined, 2);
+        assert_eq!(ran.load(Ordering::SeqCst), 1000);
+        assert_eq!(pool.inner.pending.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn work_stealing_fan_out_from_workers() {
+        let pool = Pool::with_scheduler(Scheduler::WorkStealing, None, 2, 2);
+        let handle = pool.handle();
+
+        let sum = pool.spawn(move || {
+            let children: Vec<_> = (0..100).map(|i| handle.spawn(move || i)).collect();
+            children.into_iter().map(|child| child.join().unwrap()).sum::<usize>()
+        });
+
+        assert_eq!(sum.join().unwrap(), 4950);
+
+        //子任务可能在 join 里直接执行，队列里剩下的空壳取完之后计数回到 0，不会减成负数回绕
+        let deadline = deadline();
+
+        while pool.inner.pending.load(Ordering::SeqCst) != 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(pool.inner.pending.load(Ordering::SeqCst), 0);
+        assert_eq!(pool.monitor().queued(), 0);
+    }
+
+    #[test]
+    fn priority_order() {
+        let mut classes = Classes::new(0);
+
+        for &priority in [Priority::Low, Priority::Normal, Priority::High, Priority::Normal].iter() {
+            classes.push(priority, (Instant::now(), Box::new(|| {})));
+        }
+
+        let order: Vec<Priority> = (0..4).map(|_| classes.pop(4, true).unwrap().0).collect();
+        assert_eq!(order, vec![Priority::High, Priority::Normal, Priority::Normal, Priority::Low]);
+        assert!(classes.pop(4, true).is_none());
+    }
+
+    #[test]
+    fn reserved_workers() {
+        let mut classes = Classes::new(0);
+        classes.reserved[Priority::High as usize] = 1;
+
+        for _ in 0..2 {
+            classes.push(Priority::Normal, (Instant::now(), Box::new(|| {})));
+        }
+
+        //两个线程里一个留给 High
+        assert_eq!(classes.pop(2, true).unwrap().0, Priority::Normal);
+        assert!(classes.pop(2, true).is_none());
+        assert_eq!(classes.pop(2, false).unwrap().0, Priority::Normal);
+
+        classes.push(Priority::High, (Instant::now(), Box::new(|| {})));
+        assert_eq!(classes.pop(3, true).unwrap().0, Priority::High);
+
+        classes.finish(Priority::Normal);
+        classes.finish(Priority::Normal);
+        classes.push(Priority::Low, (Instant::now(), Box::new(|| {})));
+        assert_eq!(classes.pop(2, true).unwrap().0, Priority::Low);
+    }
+
+    #[test]
+    fn reserved_worker_runs_high_only() {
+        let pool = held();
+        let (tx, rx) = mpsc::channel();
+
+        let normal = tx.clone();
+        pool.execute(move || normal.send(Priority::Normal).unwrap());
+        pool.execute_with(Priority::High, move || tx.send(Priority::High).unwrap());
+
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(Priority::High));
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        //关闭后不再预留，排队的任务执行完
+        pool.shutdown(ShutdownPolicy::Drain, deadline());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(Priority::Normal));
+    }
+}
@@ -0,0 +1,211 @@
+use std::sync::{Arc, Weak, Mutex, Condvar, PoisonError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::BinaryHeap;
+use std::cmp;
+use std::thread;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+
+use super::threadpool::FnBox;
+use super::sync::lock;
+
+struct Entry {
+    deadline: Instant,
+    seq: usize,
+    cancelled: Arc<AtomicBool>,
+    task: Box<dyn FnBox + Send>,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.deadline == other.deadline && self.seq == other.seq
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+//BinaryHeap 是大顶堆，反过来比较让最早到期的在堆顶
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> cmp::Ordering {
+        other.deadline.cmp(&self.deadline).then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+struct State {
+    heap: BinaryHeap<Entry>,
+    seq: usize,
+    started: bool,
+    shutdown: bool,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    condvar: Condvar,
+}
+
+/// 定时器，一个后台线程按到期时间执行任务，任务应尽快返回
+pub struct Timer {
+    inner: Arc<Inner>,
+}
+
+/// 定时任务句柄，到期前可以取消
+#[derive(Clone)]
+pub struct TimerHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl TimerHandle {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    heap: BinaryHeap::new(),
+                    seq: 0,
+                    started: false,
+                    shutdown: false,
+                }),
+                condvar: Condvar::new(),
+            }),
+        }
+    }
+
+    pub fn schedule<F>(&self, delay: Duration, task: F) -> TimerHandle
+        where F: FnOnce() + Send + 'static
+    {
+        self.schedule_at(Instant::now() + delay, task)
+    }
+
+    pub fn schedule_at<F>(&self, deadline: Instant, task: F) -> TimerHandle
+        where F: FnOnce() + Send + 'static
+    {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        push(&self.inner, deadline, cancelled.clone(), Box::new(task));
+
+        TimerHandle {
+            cancelled: cancelled,
+        }
+    }
+
+    /// 每隔 interval 执行一次，task 返回 false 或者句柄取消后停止
+    ///
+    /// 不占用单独的线程，定时器关闭后也停止。
+    pub fn schedule_repeat<F>(&self, interval: Duration, task: F) -> TimerHandle
+        where F: FnMut() -> bool + Send + 'static
+    {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        repeat(Arc::downgrade(&self.inner), interval, cancelled.clone(), task);
+
+        TimerHandle {
+            cancelled: cancelled,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        lock(&self.inner.state).heap.len()
+    }
+}
+
+fn push(inner: &Arc<Inner>, deadline: Instant, cancelled: Arc<AtomicBool>, task: Box<dyn FnBox + Send>) {
+    let mut state = lock(&inner.state);
+
+    state.seq += 1;
+    let seq = state.seq;
+
+    state.heap.push(Entry {
+        deadline: deadline,
+        seq: seq,
+        cancelled: cancelled,
+        task: task,
+    });
+
+    //第一次使用时才启动线程
+    if !state.started {
+        state.started = true;
+        thread(inner);
+    }
+
+    inner.condvar.notify_one();
+}
+
+//任务里只持有弱引用，否则堆里的任务和定时器互相引用，定时器永远不会释放
+fn repeat<F>(inner: Weak<Inner>, interval: Duration, cancelled: Arc<AtomicBool>, mut task: F)
+    where F: FnMut() -> bool + Send + 'static
+{
+    let strong = match inner.upgrade() {
+        Some(strong) => strong,
+        None => return,
+    };
+
+    let again = cancelled.clone();
+
+    push(&strong, Instant::now() + interval, cancelled, Box::new(move || {
+        if task() && !again.load(Ordering::Acquire) {
+            repeat(inner, interval, again, task);
+        }
+    }));
+}
+
+fn thread(inner: &Arc<Inner>) {
+    let inner = inner.clone();
+
+    thread::spawn(move || {
+        let mut state = lock(&inner.state);
+
+        loop {
+            if state.shutdown {
+                return;
+            }
+
+            let now = Instant::now();
+
+            let wait = match state.heap.peek() {
+                Some(entry) if entry.deadline <= now => None,
+                Some(entry) => Some(entry.deadline - now),
+                None => Some(Duration::from_secs(3600)),
+            };
+
+            match wait {
+                Some(wait) => {
+                    state = inner.condvar.wait_timeout(state, wait).unwrap_or_else(PoisonError::into_inner).0;
+                },
+                None => {
+                    let entry = state.heap.pop().unwrap();
+
+                    //执行任务时不持有锁，任务里可以继续添加定时任务
+                    drop(state);
+
+                    //任务 panic 不能停掉定时线程，否则后面的定时任务都不会执行
+                    if !entry.cancelled.load(Ordering::Acquire) {
+                        let task = entry.task;
+                        let _ = panic::catch_unwind(AssertUnwindSafe(|| task.call_box()));
+                    }
+
+                    state = lock(&inner.state);
+                }
+            }
+        }
+    });
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        lock(&self.inner.state).shutdown = true;
+        self.inner.condvar.notify_all();
+    }
+}